@@ -1,19 +1,24 @@
-use rusqlite::Connection;
+use std::sync::Arc;
 
-use crate::discord::relay;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone)]
+use crate::error::{RelayError, RelayResult};
+use crate::Entry;
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct User {
     pub source: String, // Source, e.g matrix, discord
     pub id: String, // Actual id
     pub ping: String, // Used to mention user
     pub tag: String, // Used to tag (kinda)
     pub display: String, // Display Name
+    pub name: String, // Account name, without the discriminator
 
-    pub avatar: Option<String>,
+    pub avatar: Option<String>, // Discord: avatar hash. Matrix: public url of the avatar
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Message {
     pub service: String,
     pub server_id: String, // Server id, if applicable (not applicable to matrix as it can only work as 1 appservice atm)
@@ -21,93 +26,150 @@ pub struct Message {
     pub id: String,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub filename: String,
+    pub url: String, // Where the file can be downloaded from, the mxc:// uri for matrix media
+    pub content_type: Option<String>,
+    pub size: u64,
+
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+    pub thumbnail_url: Option<String>, // Smaller preview of the file, if the service provides one
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct FullMessage {
     pub user: User,
     pub message: Message,
 
     pub content: String,
-    pub reply: Option<Box<Message>>
+    pub reply: Option<Box<Message>>,
+    pub attachments: Vec<Attachment>,
+    pub mentions: Vec<User>, // Users pinged in the content
+    pub thread: Option<Message>, // Discord: the thread channel (room_id is its parent). Matrix: the thread root
 }
 
-pub fn create_message(source: Message, relayed: Message)
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FullReaction {
+    pub user: User,
+    pub reaction: Message, // Discord reactions don't have ids, so the id is built from the message, user and emoji
+    pub message: Message, // The message that was reacted to
+
+    pub emoji: String, // Unicode emoji, or a :shortcode: for custom emoji
+}
+
+// An outbound send, edit or delete waiting to be delivered
+#[derive(Clone, Debug)]
+pub struct OutboxItem {
+    pub id: i64,
+    pub destination: String, // Items with the same destination are delivered in order
+    pub payload: String, // The serialized delivery
+    pub attempts: u32,
+    pub next_attempt: i64, // Unix time in milliseconds
+    pub last_error: Option<String>,
+    pub dead: bool, // Gave up on delivering it, kept so it can be looked at
+}
+
+// Returns the file and the content type reported by the server
+pub async fn download(url: String) -> RelayResult<(Vec<u8>, Option<String>)>
 {
-    let database = Connection::open("./relay.db").expect("Error loading db!");
-    database.execute("
-    INSERT OR IGNORE INTO messages (service_org, server_id_org, room_id_org, id_org, service_out, server_id_out, room_id_out, id_out)
-    VALUES (?, ?, ?, ?, ?, ?, ?, ?);",
-    (source.service, source.server_id, source.room_id, source.id, relayed.service, relayed.server_id, relayed.room_id, relayed.id)).expect("Failed to insert message into database!");
+    return download_authenticated(url, None).await;
 }
 
-pub fn message_origin(relayed: Message) -> Option<Message>
+/// The same as download, sending the token as a bearer token when there is one
+pub async fn download_authenticated(url: String, token: Option<&str>) -> RelayResult<(Vec<u8>, Option<String>)>
 {
-    println!("Origin of: {} {} {} {}", relayed.service, relayed.server_id, relayed.room_id, relayed.id);
-    let database = Connection::open("./relay.db").expect("Error loading db!");
-    let mut stmt = database.prepare("SELECT service_org, server_id_org, room_id_org, id_org FROM messages WHERE service_out=:s AND server_id_out=:sid AND room_id_out=:rid AND id_out=:id").unwrap();
-    let iter = stmt.query_map(&[
-        (":s", relayed.service.as_str()),
-        (":sid", relayed.server_id.as_str()),
-        (":rid", relayed.room_id.as_str()),
-        (":id", relayed.id.as_str()),
-    ], |row| {
-        Ok(Message {
-            service: row.get(0)?,
-            server_id: row.get(1)?,
-            room_id: row.get(2)?,
-            id: row.get(3)?,
-        })
-    }).unwrap();
-
-    let mut out: Vec<Message> = Vec::new();
-    for msg in iter {
-        out.push(msg.unwrap());
+    let mut request = reqwest::Client::new().get(url.clone());
+    if token.is_some() {
+        request = request.bearer_auth(token.unwrap());
     }
-
-    if out.len() == 0 {
-        return None;
+    let res = request.send().await?;
+    if !res.status().is_success() {
+        return Err(RelayError::from_status(res.status().as_u16(), format!("download of {}", url), None));
     }
 
-    assert_eq!(out.len(), 1);
-
-    return Some(out[0].clone());
+    let content_type = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_owned());
+    let data = res.bytes().await?;
+    return Ok((data.to_vec(), content_type));
 }
 
-pub fn message_relays(source: Message) -> Vec<Message>
-{
-    let database = Connection::open("./relay.db").expect("Error loading db!");
-    let mut stmt = database.prepare("SELECT service_out, server_id_out, room_id_out, id_out FROM messages WHERE service_org=:s AND server_id_org=:sid AND room_id_org=:rid AND id_org=:id").unwrap();
-    let iter = stmt.query_map(&[
-        (":s", source.service.as_str()),
-        (":sid", source.server_id.as_str()),
-        (":rid", source.room_id.as_str()),
-        (":id", source.id.as_str()),
-    ], |row| {
-        Ok(Message {
-            service: row.get(0)?,
-            server_id: row.get(1)?,
-            room_id: row.get(2)?,
-            id: row.get(3)?,
-        })
-    }).unwrap();
-
-    let mut out: Vec<Message> = Vec::new();
-    for msg in iter {
-        out.push(msg.unwrap());
-    }
 
-    return out;
+/// Where the relay keeps which messages, reactions and threads belong together, and everything else it remembers.
+/// Implemented in storage::sqlite and storage::postgres
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn create_message(&self, source: Message, relayed: Message) -> RelayResult<()>;
+    async fn message_origin(&self, relayed: Message) -> RelayResult<Option<Message>>;
+    /// Copies of a message, in the order they were relayed
+    async fn message_relays(&self, source: Message) -> RelayResult<Vec<Message>>;
+    /// Forgets a message and every relayed copy of it, whichever side it is given from
+    async fn delete_message(&self, msg: Message) -> RelayResult<()>;
+    /// Forgets a single relayed copy of a message, e.g. a part of a split message that was edited away
+    async fn delete_relayed_message(&self, relayed: Message) -> RelayResult<()>;
+
+    async fn create_reaction(&self, source: Message, relayed: Message) -> RelayResult<()>;
+    async fn reaction_relays(&self, source: Message) -> RelayResult<Vec<Message>>;
+    /// Several matrix users reacting with the same emoji share one discord reaction, so there can be more than one origin
+    async fn reaction_origins(&self, relayed: Message) -> RelayResult<Vec<Message>>;
+    async fn delete_reaction(&self, reaction: Message) -> RelayResult<()>;
+
+    /// Threads are always stored with the discord thread as the origin and the matrix thread root as the relay,
+    /// whichever side they were started on
+    async fn create_thread(&self, thread: Message, root: Message) -> RelayResult<()>;
+    async fn thread_root(&self, thread: Message) -> RelayResult<Option<Message>>;
+    async fn root_thread(&self, root: Message) -> RelayResult<Option<Message>>;
+
+    async fn avatar_mxc(&self, hash: &str) -> RelayResult<Option<String>>;
+    async fn create_avatar(&self, hash: &str, mxc: &str) -> RelayResult<()>;
+    /// Hash of the avatar last set on a puppet
+    async fn puppet_avatar(&self, user_id: &str) -> RelayResult<Option<String>>;
+    async fn set_puppet_avatar(&self, user_id: &str, hash: &str) -> RelayResult<()>;
+    /// Display name last set on a puppet in a room, or globally when room_id is empty
+    async fn puppet_name(&self, user_id: &str, room_id: &str) -> RelayResult<Option<String>>;
+    async fn set_puppet_name(&self, user_id: &str, room_id: &str, displayname: &str) -> RelayResult<()>;
+    /// Profile changes are copied into every room by the homeserver, which overwrites the per-room names
+    async fn clear_puppet_room_names(&self, user_id: &str) -> RelayResult<()>;
+
+    /// Every bridged room, the ones from the config included
+    async fn rooms(&self) -> RelayResult<Vec<Entry>>;
+    /// Bridges the room, replacing whatever its discord channel or matrix room was bridged to before
+    async fn create_room(&self, room: &Entry) -> RelayResult<()>;
+    async fn delete_room(&self, matrix: &str) -> RelayResult<()>;
+    /// The webhook the relay uses in a discord channel, as (id, token)
+    async fn channel_webhook(&self, channel: &str) -> RelayResult<Option<(String, String)>>;
+    async fn set_channel_webhook(&self, channel: &str, id: &str, token: &str) -> RelayResult<()>;
+
+    /// Returns the id of the new item
+    async fn enqueue_outbox(&self, destination: &str, payload: &str, now: i64) -> RelayResult<i64>;
+    /// The oldest pending item of every destination, later items wait until it is delivered or dead
+    async fn outbox_heads(&self) -> RelayResult<Vec<OutboxItem>>;
+    async fn outbox_done(&self, id: i64) -> RelayResult<()>;
+    async fn outbox_retry(&self, id: i64, attempts: u32, next_attempt: i64, error: &str) -> RelayResult<()>;
+    async fn outbox_dead(&self, id: i64, attempts: u32, error: &str) -> RelayResult<()>;
+    async fn dead_letters(&self) -> RelayResult<Vec<OutboxItem>>;
+    /// Puts a dead item back in the queue. It goes back in its original place, so it is delivered before anything newer
+    async fn requeue_dead_letter(&self, id: i64, now: i64) -> RelayResult<()>;
+
+    /// Finds the copy of a message on another service, whether the message was relayed from there or to there.
+    async fn message_counterpart(&self, msg: Message, service: &str) -> RelayResult<Option<Message>>
+    {
+        let relayed = self.message_relays(msg.clone()).await?.into_iter().rev().find(|relayed| relayed.service == service);
+        if relayed.is_some() {
+            return Ok(relayed);
+        }
+
+        let origin = self.message_origin(msg).await?;
+        if origin.is_some() && origin.as_ref().unwrap().service == service {
+            return Ok(origin);
+        }
+        return Ok(None);
+    }
 }
 
-pub fn delete_message(msg: Message)
-{
-    let mut id = msg.id.clone();
-    let origin = message_origin(msg.clone());
-    if origin.is_some() {
-        id = origin.unwrap().id;
-    }
-    let id = id.as_str();
-    let database = Connection::open("./relay.db").expect("Error loading db!");
-    database.execute("DELETE FROM messages WHERE id_org=:id OR id_new=:id", 
-    (":id", id),
-    ); // should ignore errors (e.g if message didn't exist in db)
-}
\ No newline at end of file
+/// The storage everything shares, cloning it is cheap
+pub type Store = Arc<dyn Storage>;
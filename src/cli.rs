@@ -0,0 +1,196 @@
+// Command line arguments, and where the relay keeps its files. Everything defaults to the data directory, which is
+// the working directory unless --data-dir says otherwise.
+
+use std::path::Path;
+use std::sync::RwLock;
+
+use anyhow::anyhow;
+
+pub const USAGE: &str = "Usage: matrix_discord_relay [options] [command]
+
+Commands:
+  run                    Run the relay (the default)
+  check-config           Check the config, the registration and the database, then exit
+  generate-registration  Write a registration for the config, with new tokens
+  check-migrations       List the migrations starting would apply, without applying them
+  import-sqlite [path]   Copy a sqlite database (default <data dir>/relay.db) into the postgres database_url
+  dead-letters           List the deliveries that were given up on
+  requeue <id...|all>    Try the given dead letters again
+
+Options:
+  --config <path>        Config file, default <data dir>/config.toml
+  --registration <path>  Appservice registration, default <data dir>/appservice-registration.yaml
+  --database <url>       Sqlite file or postgres url, overrides database_url
+  --data-dir <path>      Where the files above are looked for, default the working directory
+  -h, --help             Show this
+
+Any top level config key can be set with a RELAY_ environment variable, e.g. RELAY_DISCORD_TOKEN.
+RELAY_ADMINS takes a comma separated list.";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run,
+    CheckConfig,
+    GenerateRegistration,
+    CheckMigrations,
+    ImportSqlite(Option<String>),
+    DeadLetters,
+    // None requeues every dead letter
+    Requeue(Option<Vec<i64>>),
+    Help,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Paths {
+    pub data_dir: String,
+    pub config: String,
+    pub registration: String,
+    // Only set by --database, database_url in the config is used otherwise
+    pub database: Option<String>,
+}
+
+impl Default for Paths {
+    fn default() -> Self {
+        return Paths::in_dir(".");
+    }
+}
+
+impl Paths {
+    pub fn in_dir(data_dir: &str) -> Paths {
+        return Paths {
+            data_dir: data_dir.to_owned(),
+            config: join(data_dir, "config.toml"),
+            registration: join(data_dir, "appservice-registration.yaml"),
+            database: None,
+        };
+    }
+
+    /// The sqlite database used when neither --database nor database_url is set
+    pub fn default_database(&self) -> String {
+        return join(&self.data_dir, "relay.db");
+    }
+}
+
+fn join(dir: &str, file: &str) -> String {
+    return Path::new(dir).join(file).to_string_lossy().into_owned();
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub command: Command,
+    pub paths: Paths,
+}
+
+lazy_static! {
+    static ref PATHS: RwLock<Paths> = RwLock::new(Paths::default());
+}
+
+/// Where the relay's files are, the defaults until main sets them from the arguments
+pub fn paths() -> Paths {
+    return PATHS.read().unwrap().clone();
+}
+
+pub fn set_paths(paths: Paths) {
+    *PATHS.write().unwrap() = paths;
+}
+
+/// Parses the arguments, without the name of the binary
+pub fn parse_args(args: &[String]) -> anyhow::Result<Args> {
+    let mut command: Option<Command> = None;
+    // --help wins over anything else given with it
+    let mut help = false;
+    let mut data_dir: Option<String> = None;
+    let mut config: Option<String> = None;
+    let mut registration: Option<String> = None;
+    let mut database: Option<String> = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        // Both "--config path" and "--config=path" work
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value.to_owned())),
+            _ => (arg, None),
+        };
+
+        let target = match name {
+            "--config" => Some(&mut config),
+            "--registration" => Some(&mut registration),
+            "--database" => Some(&mut database),
+            "--data-dir" => Some(&mut data_dir),
+            _ => None,
+        };
+        if target.is_some() {
+            let value = if inline.is_some() { inline } else { i += 1; args.get(i).cloned() };
+            if value.is_none() || value.as_ref().unwrap() == "" {
+                return Err(anyhow!("{} needs a value\n\n{}", name, USAGE));
+            }
+            *target.unwrap() = value;
+            i += 1;
+            continue;
+        }
+
+        let next = match arg {
+            "-h" | "--help" => Command::Help,
+            "run" => Command::Run,
+            "check-config" => Command::CheckConfig,
+            "generate-registration" => Command::GenerateRegistration,
+            // The flags are what these were before there were subcommands
+            "check-migrations" | "--check-migrations" => Command::CheckMigrations,
+            "import-sqlite" | "--import-sqlite" => {
+                let path = args.get(i + 1).filter(|path| !path.starts_with("-"));
+                if path.is_some() {
+                    i += 1;
+                }
+                Command::ImportSqlite(path.cloned())
+            }
+            "dead-letters" => Command::DeadLetters,
+            "requeue" => {
+                let mut ids: Vec<i64> = Vec::new();
+                let mut all = false;
+                while args.get(i + 1).filter(|id| !id.starts_with("-")).is_some() {
+                    i += 1;
+                    if args[i] == "all" {
+                        all = true;
+                        continue;
+                    }
+                    let id = args[i].parse::<i64>();
+                    if id.is_err() {
+                        return Err(anyhow!("\"{}\" isn't the id of a dead letter\n\n{}", args[i], USAGE));
+                    }
+                    ids.push(id.unwrap());
+                }
+                if !all && ids.len() == 0 {
+                    return Err(anyhow!("requeue needs the ids of the dead letters, or all\n\n{}", USAGE));
+                }
+                Command::Requeue(if all { None } else { Some(ids) })
+            }
+            _ => return Err(anyhow!("Unknown argument \"{}\"\n\n{}", arg, USAGE)),
+        };
+        if next == Command::Help {
+            help = true;
+        } else if command.is_some() {
+            return Err(anyhow!("Only one command can be given, got \"{}\" after another\n\n{}", arg, USAGE));
+        } else {
+            command = Some(next);
+        }
+        i += 1;
+    }
+
+    let mut paths = Paths::in_dir(data_dir.as_deref().unwrap_or("."));
+    if config.is_some() {
+        paths.config = config.unwrap();
+    }
+    if registration.is_some() {
+        paths.registration = registration.unwrap();
+    }
+    paths.database = database;
+
+    if help {
+        command = Some(Command::Help);
+    }
+    return Ok(Args {
+        command: command.unwrap_or(Command::Run),
+        paths: paths,
+    });
+}
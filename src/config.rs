@@ -0,0 +1,300 @@
+// The config is read on startup, and again whenever the file changes. A config that doesn't parse or isn't valid is
+// refused with what is wrong with it, and the one already running is kept.
+
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context};
+
+use crate::chat_service::Store;
+use crate::{cli, discord, matrix, rooms, Entry, Outer};
+
+// Environment variables starting with this set config keys, e.g. RELAY_DISCORD_TOKEN sets discord_token
+pub const ENV_PREFIX: &str = "RELAY_";
+// The keys environment variables can set. Others starting with the prefix belong to something else, e.g. kubernetes
+// sets RELAY_PORT and RELAY_SERVICE_HOST for a service called relay
+const ENV_KEYS: &[&str] = &[
+    "discord_token", "host", "homeserver_url", "server_name", "media_url", "puppet_prefix", "displayname_template",
+    "database_url", "failure_notices", "admins", "room",
+];
+// How often the file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref CURRENT: RwLock<Option<Arc<Outer>>> = RwLock::new(None);
+}
+
+/// The config in use. Don't hold on to it for longer than needed, or changes won't be seen
+pub fn config() -> Arc<Outer> {
+    let current = CURRENT.read().unwrap().clone();
+    if current.is_some() {
+        return current.unwrap();
+    }
+    // Only tests get here, everything else calls init first so an invalid config is an error rather than a panic
+    let loaded = Arc::new(load(&cli::paths().config).unwrap_or_else(|err| panic!("{:#}", err)));
+    *CURRENT.write().unwrap() = Some(loaded.clone());
+    return loaded;
+}
+
+/// Loads the config for startup
+pub fn init() -> anyhow::Result<Arc<Outer>> {
+    let loaded = Arc::new(load(&cli::paths().config)?);
+    *CURRENT.write().unwrap() = Some(loaded.clone());
+    return Ok(loaded);
+}
+
+/// Reads the config file, with the RELAY_ environment variables on top
+pub fn load(path: &str) -> anyhow::Result<Outer> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+    let overrides = env_overrides(std::env::vars());
+    return parse_with_overrides(&text, &overrides).with_context(|| format!("Invalid config in {}", path));
+}
+
+/// The environment variables that set config keys, the other RELAY_ ones are skipped with a warning
+pub fn env_overrides(vars: impl Iterator<Item = (String, String)>) -> Vec<(String, String)> {
+    let mut overrides = Vec::new();
+    for (name, value) in vars.filter(|(name, _)| name.starts_with(ENV_PREFIX)) {
+        let key = name[ENV_PREFIX.len()..].to_lowercase();
+        if !ENV_KEYS.contains(&key.as_str()) {
+            println!("Ignoring {}, {} isn't a config key", name, key);
+            continue;
+        }
+        overrides.push((name, value));
+    }
+    return overrides;
+}
+
+pub fn parse(text: &str) -> anyhow::Result<Outer> {
+    return parse_with_overrides(text, &[]);
+}
+
+/// Parses a config, with (RELAY_ name, value) pairs replacing the keys they name
+pub fn parse_with_overrides(text: &str, overrides: &[(String, String)]) -> anyhow::Result<Outer> {
+    // Going through a toml::Value loses the line numbers of errors, so that is only done when something is overridden
+    let config: Outer = if overrides.len() == 0 {
+        toml::from_str(text)?
+    } else {
+        let mut table: toml::Table = toml::from_str(text)?;
+        for (name, value) in overrides.iter() {
+            let key = name.strip_prefix(ENV_PREFIX).unwrap_or(name).to_lowercase();
+            table.insert(key.clone(), override_value(&key, value).with_context(|| format!("Invalid {}", name))?);
+        }
+        toml::Value::Table(table).try_into()?
+    };
+    validate(&config)?;
+    return Ok(config);
+}
+
+// Environment variables are all text, so what they are turned into depends on the key
+fn override_value(key: &str, value: &str) -> anyhow::Result<toml::Value> {
+    match key {
+        "discord_token" | "host" | "homeserver_url" | "server_name" | "media_url" | "puppet_prefix"
+        | "displayname_template" | "database_url" => {
+            return Ok(toml::Value::String(value.to_owned()));
+        }
+        "failure_notices" => {
+            let enabled = value.parse::<bool>().map_err(|_| anyhow!("\"{}\" has to be true or false", value))?;
+            return Ok(toml::Value::Boolean(enabled));
+        }
+        "admins" => {
+            let admins = value.split(',').map(|admin| admin.trim()).filter(|admin| *admin != "");
+            return Ok(toml::Value::Array(admins.map(|admin| toml::Value::String(admin.to_owned())).collect()));
+        }
+        // Rooms are a list of tables, which doesn't fit in one variable
+        "room" => return Err(anyhow!("rooms can only be set in the config file or with the bridge commands")),
+        _ => return Err(anyhow!("{} isn't a config key", key)),
+    }
+}
+
+fn is_discord_id(id: &str) -> bool {
+    return id.parse::<u64>().is_ok();
+}
+
+// Matrix ids are a sigil, a local part and the server name
+fn is_matrix_id(id: &str, sigil: char) -> bool {
+    return id.starts_with(sigil) && id.len() > 1 && id.find(':').map(|colon| colon > 1 && colon < id.len() - 1).unwrap_or(false);
+}
+
+/// Checks what serde can't, so a mistake is found when the config is loaded instead of when it is first used
+pub fn validate(config: &Outer) -> anyhow::Result<()> {
+    if config.discord_token.trim() == "" {
+        return Err(anyhow!("discord_token is empty"));
+    }
+    let port = config.host.rsplit_once(':').map(|(_, port)| port.parse::<u16>());
+    if port.is_none() || port.unwrap().is_err() {
+        return Err(anyhow!("host \"{}\" has to be an address and a port, e.g. 0.0.0.0:8080", config.host));
+    }
+    if !config.homeserver_url.starts_with("http://") && !config.homeserver_url.starts_with("https://") {
+        return Err(anyhow!("homeserver_url \"{}\" has to be an http:// or https:// url", config.homeserver_url));
+    }
+    let media_url = config.media_url.as_deref().unwrap_or("https://");
+    if !media_url.starts_with("http://") && !media_url.starts_with("https://") {
+        return Err(anyhow!("media_url \"{}\" has to be an http:// or https:// url", media_url));
+    }
+    if config.server_name.trim() == "" || config.server_name.contains('/') {
+        return Err(anyhow!("server_name \"{}\" has to be a server name like example.com", config.server_name));
+    }
+
+    // It is the start of matrix user ids, which only allow these
+    let prefix = config.puppet_prefix.as_deref();
+    if prefix.is_some() && (prefix == Some("") || !prefix.unwrap().chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._=-/".contains(c))) {
+        return Err(anyhow!("puppet_prefix \"{}\" can only have a-z, 0-9 and ._=-/ in it", prefix.unwrap()));
+    }
+
+    for admin in config.admins.as_deref().unwrap_or_default() {
+        if !is_matrix_id(admin, '@') {
+            return Err(anyhow!("admins: \"{}\" isn't a matrix user id like @admin:example.com", admin));
+        }
+    }
+
+    for (i, room) in config.room.iter().enumerate() {
+        if !is_discord_id(&room.discord) {
+            return Err(anyhow!("room {}: discord \"{}\" isn't a channel id", i + 1, room.discord));
+        }
+        if !is_discord_id(&room.discord_guild) {
+            return Err(anyhow!("room {}: discord_guild \"{}\" isn't a server id", i + 1, room.discord_guild));
+        }
+        if !is_matrix_id(&room.matrix, '!') {
+            return Err(anyhow!("room {}: matrix \"{}\" isn't a room id like !abc:example.com", i + 1, room.matrix));
+        }
+        let webhook = room.webhook.as_deref().unwrap_or("https://discord.com/api/webhooks/");
+        if !webhook.starts_with("https://discord.com/api/webhooks/") && !webhook.starts_with("https://discordapp.com/api/webhooks/") {
+            return Err(anyhow!("room {}: webhook \"{}\" isn't a discord webhook url", i + 1, webhook));
+        }
+
+        // A channel or room can only be bridged once
+        let earlier = config.room[..i].iter().position(|other| other.discord == room.discord || other.matrix == room.matrix);
+        if earlier.is_some() {
+            return Err(anyhow!("room {} bridges the same channel or room as room {}", i + 1, earlier.unwrap() + 1));
+        }
+    }
+    return Ok(());
+}
+
+/// What changed between two configs
+#[derive(Debug, Default)]
+pub struct Changes {
+    // New rooms, and rooms of which anything else changed
+    pub rooms_added: Vec<Entry>,
+    pub rooms_removed: Vec<Entry>,
+    pub token_changed: bool,
+    // Settings that are only read on startup
+    pub restart_needed: Vec<&'static str>,
+}
+
+fn same_room(a: &Entry, b: &Entry) -> bool {
+    return a.discord == b.discord
+        && a.discord_guild == b.discord_guild
+        && a.matrix == b.matrix
+        && a.webhook == b.webhook
+        && a.max_upload_size == b.max_upload_size;
+}
+
+pub fn diff(old: &Outer, new: &Outer) -> Changes {
+    let mut changes = Changes::default();
+    changes.rooms_added = new.room.iter().filter(|room| !old.room.iter().any(|old_room| same_room(old_room, room))).cloned().collect();
+    // A room that is still there with other settings is replaced when it is added again
+    changes.rooms_removed = old
+        .room
+        .iter()
+        .filter(|room| !new.room.iter().any(|new_room| new_room.discord == room.discord || new_room.matrix == room.matrix))
+        .cloned()
+        .collect();
+    changes.token_changed = old.discord_token != new.discord_token;
+
+    if old.host != new.host {
+        changes.restart_needed.push("host");
+    }
+    if old.homeserver_url != new.homeserver_url {
+        changes.restart_needed.push("homeserver_url");
+    }
+    if old.server_name != new.server_name {
+        changes.restart_needed.push("server_name");
+    }
+    if old.puppet_prefix != new.puppet_prefix {
+        changes.restart_needed.push("puppet_prefix");
+    }
+    if old.database_url != new.database_url {
+        changes.restart_needed.push("database_url");
+    }
+    return changes;
+}
+
+/// Puts back the settings that are only read on startup, so config() keeps saying what the relay is running with
+pub fn keep_restart_only(old: &Outer, new: &mut Outer) {
+    new.host = old.host.clone();
+    new.homeserver_url = old.homeserver_url.clone();
+    new.server_name = old.server_name.clone();
+    new.puppet_prefix = old.puppet_prefix.clone();
+    new.database_url = old.database_url.clone();
+}
+
+/// Reads the config again, and applies what changed if it is valid
+pub async fn reload(store: &Store) {
+    let new = load(&cli::paths().config);
+    if new.is_err() {
+        println!("Keeping the current config: {:#}", new.err().unwrap());
+        return;
+    }
+    let mut new = new.unwrap();
+    let old = config();
+    let changes = diff(&old, &new);
+    // A token discord won't take would leave the relay without a discord side until the next restart
+    if changes.token_changed {
+        let checked = discord::bot::check_token(&new.discord_token).await;
+        if checked.is_err() {
+            println!("Keeping the current config, discord refused the new discord_token: {}", checked.err().unwrap());
+            return;
+        }
+    }
+    keep_restart_only(&old, &mut new);
+    *CURRENT.write().unwrap() = Some(Arc::new(new));
+    println!("Reloaded the config");
+
+    for room in changes.rooms_removed.iter() {
+        let res = rooms::unlink(store, &room.matrix).await;
+        if res.is_err() {
+            println!("Failed to stop bridging {}: {}", room.matrix, res.err().unwrap());
+            continue;
+        }
+        println!("Stopped bridging {} -> {}", room.discord, room.matrix);
+    }
+    for room in changes.rooms_added.iter() {
+        let res = rooms::link(store, rooms::from_config(room)).await;
+        if res.is_err() {
+            println!("Failed to bridge {}: {}", room.matrix, res.err().unwrap());
+            continue;
+        }
+        let joined = matrix::relay::join_room(&room.matrix).await;
+        if joined.is_err() {
+            println!("Failed to join {}: {}", room.matrix, joined.err().unwrap());
+        }
+        println!("Bridged {} -> {}", room.discord, room.matrix);
+    }
+
+    if changes.token_changed {
+        discord::bot::reconnect();
+    }
+    for setting in changes.restart_needed.iter() {
+        println!("{} changed, it only takes effect after a restart", setting);
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    return std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+}
+
+/// Reloads the config whenever the file changes
+pub async fn watch(store: Store) {
+    let path = cli::paths().config;
+    let mut last_modified = modified(&path);
+    loop {
+        tokio::time::sleep(WATCH_INTERVAL).await;
+        let modified = modified(&path);
+        if modified.is_none() || modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+        reload(&store).await;
+    }
+}
@@ -1,18 +1,42 @@
+use std::collections::HashMap;
 use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use serenity::model::prelude::{ChannelId, MessageId, MessageUpdateEvent};
+use serenity::model::prelude::{AuditLogEntryId, Channel, ChannelId, ChannelType, MessageId, MessageUpdateEvent, Reaction, ReactionType, RoleId, UserId};
 use serenity::{async_trait, model::prelude::GuildId};
-use serenity::model::channel::Message;
+use serenity::model::channel::{Attachment, Message};
+use serenity::model::application::interaction::Interaction;
 use serenity::model::gateway::Ready;
 use serenity::prelude::*;
 
+use crate::error::{RelayError, RelayResult};
+use crate::outbox::{self, Delivery};
+use crate::{rooms, sequencer};
 use crate::{matrix, Entry};
-use crate::{CONFIG, chat_service::{self, FullMessage, User}};
+use super::commands;
+use super::format::{self, Mention};
+use crate::config::config;
+use crate::chat_service::{self, FullMessage, FullReaction, Store, User};
 
-struct Handler;
+struct Handler {
+    store: Store,
+}
+
+// How long failure notices stay in the channel
+const FAILURE_NOTICE_LIFETIME: Duration = Duration::from_secs(30);
+// Discord writes the audit log entry of a deletion a moment after sending the event
+const AUDIT_LOG_DELAY: Duration = Duration::from_secs(1);
+// How old, in seconds, an audit log entry seen for the first time can be and still be for the deletion being handled
+const AUDIT_LOG_WINDOW: i64 = 10;
+// Audit log action types
+const MESSAGE_DELETE: u8 = 72;
+const MESSAGE_BULK_DELETE: u8 = 73;
 
 lazy_static! {
     pub static ref CONTEXT: std::sync::Mutex<Option<Context>> = std::sync::Mutex::new(None);
+    // Count each message deletion audit log entry had when it was last looked at, by guild and action, then entry id
+    static ref DELETION_COUNTS: std::sync::Mutex<HashMap<(u64, u8), HashMap<u64, u64>>> = std::sync::Mutex::new(HashMap::new());
+    static ref RECONNECT: tokio::sync::Notify = tokio::sync::Notify::new();
 }
 
 // I pass guild id as argument as replies do not have guild id correctly set
@@ -27,6 +51,39 @@ fn message_to_relayed_message(msg: Message, guild_id: String) -> chat_service::M
     return relay_msg;
 }
 
+// Discord's media proxy can resize images and grab the first frame of videos, so we use it for thumbnails
+fn attachment_thumbnail_url(attach: &Attachment) -> Option<String> {
+    let content_type = attach.content_type.clone().unwrap_or("".to_owned());
+    if attach.width.is_none() || attach.height.is_none() {
+        return None;
+    }
+
+    let (width, height) = (attach.width.unwrap(), attach.height.unwrap());
+    let scale = f64::min(1.0, f64::min(800.0 / width as f64, 600.0 / height as f64));
+    let thumb_width = ((width as f64 * scale) as u64).max(1);
+    let thumb_height = ((height as f64 * scale) as u64).max(1);
+
+    if content_type.starts_with("image/") {
+        return Some(format!("{}?width={}&height={}", attach.proxy_url, thumb_width, thumb_height));
+    }
+    if content_type.starts_with("video/") {
+        return Some(format!("{}?format=jpeg&width={}&height={}", attach.proxy_url, thumb_width, thumb_height));
+    }
+    return None;
+}
+
+fn attachment_to_relayed_attachment(attach: &Attachment) -> chat_service::Attachment {
+    return chat_service::Attachment {
+        filename: attach.filename.clone(),
+        url: attach.url.clone(),
+        content_type: attach.content_type.clone(),
+        size: attach.size,
+        width: attach.width,
+        height: attach.height,
+        thumbnail_url: attachment_thumbnail_url(attach),
+    };
+}
+
 async fn author_to_user(author: serenity::model::prelude::User) -> User {
     return User {
         source: "discord".to_string(), // Source, e.g matrix, discord
@@ -34,12 +91,14 @@ async fn author_to_user(author: serenity::model::prelude::User) -> User {
         ping: format!("<@{}>", author.id.to_string()), // Used to mention user
         tag: format!("{}", author.tag()), // Used to tag (kinda)
         display: author.name.to_owned(), // Display Name
+        name: author.name.to_owned(),
         avatar: author.avatar
     };
 }
 
-async fn message_to_full_message(msg: Message) -> chat_service::FullMessage {
-    let ctx = (*(CONTEXT.lock().unwrap())).clone().unwrap();
+// Only guild messages are relayed, a DM has nowhere to go
+async fn message_to_full_message(ctx: &Context, msg: Message) -> RelayResult<chat_service::FullMessage> {
+    let guild_id = msg.guild_id.ok_or(RelayError::Invalid(format!("message {} isn't in a server", msg.id)))?;
     let nick = msg.clone().author_nick(ctx.http.clone()).await.clone();
 
 
@@ -55,37 +114,323 @@ async fn message_to_full_message(msg: Message) -> chat_service::FullMessage {
         user.display = nick.unwrap().to_owned();
     }
 
-    let relay_msg = message_to_relayed_message(msg.clone(), msg.guild_id.unwrap().to_string());
+    let relay_msg = message_to_relayed_message(msg.clone(), guild_id.to_string());
+
+    let mut mentions: Vec<User> = Vec::new();
+    for mentioned in msg.mentions.iter() {
+        let mut mentioned_user = author_to_user(mentioned.clone()).await;
+        let mentioned_nick = mentioned.nick_in(ctx.http.clone(), guild_id).await;
+        if mentioned_nick.is_some() {
+            mentioned_user.display = mentioned_nick.unwrap();
+        }
+        mentions.push(mentioned_user);
+    }
 
     let mut reply: Option<Box<chat_service::Message>> = None;
     if msg.referenced_message.is_some() {
         //TODO: This may be recursive...
         let replyed_msg = *(msg.referenced_message.unwrap());
-        reply = Some(Box::new(message_to_relayed_message(replyed_msg, msg.guild_id.unwrap().to_string())));
+        reply = Some(Box::new(message_to_relayed_message(replyed_msg, guild_id.to_string())));
     }
 
     let full_msg = FullMessage {
         user: user,
         message: relay_msg,
         content: msg.content.clone(),
-        reply: reply
+        reply: reply,
+        attachments: msg.attachments.iter().map(attachment_to_relayed_attachment).collect(),
+        mentions: mentions,
+        thread: None,
     };
 
-    return full_msg;
+    return Ok(full_msg);
+}
+
+/// Whether a deletion audit log entry is for the deletion that just happened. Discord doesn't add a new entry every time a
+/// moderator deletes a message by the same user in the same channel, it counts up the last one instead
+pub fn is_new_deletion(previous_count: Option<u64>, count: u64, age: i64) -> bool {
+    if previous_count.is_some() {
+        return count > previous_count.unwrap();
+    }
+    return age <= AUDIT_LOG_WINDOW;
+}
+
+/// Forgets the entries that are older than the audit window and no longer among the newest ones, which is all that is
+/// read of the audit log, so they can't count up where they'd be seen again
+pub fn prune_deletion_counts(counts: &mut HashMap<u64, u64>, seen: &[u64], now: i64) {
+    counts.retain(|id, _| seen.contains(id) || now - AuditLogEntryId(*id).created_at().unix_timestamp() <= AUDIT_LOG_WINDOW);
+}
+
+pub fn deletion_reason(moderator: &str, reason: Option<&str>) -> String {
+    if reason.is_some() && reason.unwrap().trim() != "" {
+        return format!("Deleted by {} on Discord: {}", moderator, reason.unwrap().trim());
+    }
+    return format!("Deleted by {} on Discord", moderator);
+}
+
+// The moderator who deleted messages in a channel and the redaction reason, from the audit log. Nothing is logged when
+// people delete their own messages, so those (and any deletion the audit log can't be read for) come back as None
+async fn find_deletion(ctx: &Context, guild_id: GuildId, channel_id: ChannelId, bulk: bool) -> (Option<User>, Option<String>) {
+    tokio::time::sleep(AUDIT_LOG_DELAY).await;
+    let action = if bulk { MESSAGE_BULK_DELETE } else { MESSAGE_DELETE };
+    let logs = guild_id.audit_logs(&ctx.http, Some(action), None, None, Some(10)).await;
+    if logs.is_err() {
+        println!("Failed to read the audit log of {}: {}", guild_id, logs.err().unwrap());
+        return (None, None);
+    }
+    let logs = logs.unwrap();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs() as i64).unwrap_or(0);
+
+    let mut deletion = None;
+    {
+        let mut all_counts = DELETION_COUNTS.lock().unwrap();
+        let counts = all_counts.entry((guild_id.0, action)).or_default();
+        // Newest first. Every entry's count is remembered, even once the deletion is found
+        for entry in logs.entries.iter() {
+            // Bulk deletions target the channel, single ones the author of the message
+            let channel = if bulk {
+                entry.target_id
+            } else {
+                entry.options.as_ref().and_then(|options| options.channel_id).map(|channel| channel.0)
+            };
+            if channel != Some(channel_id.0) {
+                continue;
+            }
+
+            let count = entry.options.as_ref().and_then(|options| options.count).unwrap_or(1);
+            let previous_count = counts.insert(entry.id.0, count);
+            let age = now - entry.id.created_at().unix_timestamp();
+            if deletion.is_none() && is_new_deletion(previous_count, count, age) {
+                deletion = Some((entry.user_id, entry.reason.clone()));
+            }
+        }
+        let seen: Vec<u64> = logs.entries.iter().map(|entry| entry.id.0).collect();
+        prune_deletion_counts(counts, &seen, now);
+    }
+    if deletion.is_none() {
+        return (None, None);
+    }
+    let (moderator_id, reason) = deletion.unwrap();
+
+    let moderator = moderator_id.to_user(&ctx).await;
+    if moderator.is_err() {
+        println!("Failed to look up moderator {}: {}", moderator_id, moderator.err().unwrap());
+        return (None, None);
+    }
+    let moderator = moderator.unwrap();
+    let nick = moderator.nick_in(&ctx.http, guild_id).await;
+    let mut moderator = author_to_user(moderator).await;
+    if nick.is_some() {
+        moderator.display = nick.unwrap();
+    }
+
+    let reason = deletion_reason(&moderator.display, reason.as_deref());
+    return (Some(moderator), Some(reason));
+}
+
+// The message as it is relayed, or None if its channel isn't bridged
+async fn bridged_message(ctx: &Context, msg: Message) -> Option<FullMessage> {
+    let room = find_room(ctx, msg.channel_id).await;
+    if room.is_none() {
+        return None;
+    }
+    let (room, thread) = room.unwrap();
+
+    let relay_msg = message_to_full_message(ctx, msg).await;
+    if relay_msg.is_err() {
+        println!("Not relaying a message: {}", relay_msg.err().unwrap());
+        return None;
+    }
+    let mut relay_msg = relay_msg.unwrap();
+    if thread.is_some() {
+        relay_msg.thread = Some(chat_service::Message {
+            service: "discord".to_owned(),
+            server_id: room.discord_guild.clone(),
+            room_id: room.discord.clone(),
+            id: thread.unwrap().to_string(),
+        });
+    }
+    return Some(relay_msg);
+}
+
+pub fn avatar_url(user_id: &str, hash: &str) -> String {
+    // Animated avatars start with a_
+    let extension = if hash.starts_with("a_") { "gif" } else { "png" };
+    return format!("https://cdn.discordapp.com/avatars/{}/{}.{}?size=512", user_id, hash, extension);
+}
+
+// Discord reactions don't have an id, so we make one which can be turned back into the reaction
+pub fn reaction_id(message_id: MessageId, user_id: UserId, emoji: &ReactionType) -> String {
+    return format!("{}:{}:{}", message_id, user_id, emoji);
+}
+
+// Custom emoji only exist on discord, so matrix gets their shortcode
+fn reaction_emoji(emoji: &ReactionType) -> String {
+    match emoji {
+        ReactionType::Custom { name, .. } => format!(":{}:", name.clone().unwrap_or("emoji".to_owned())),
+        _ => emoji.to_string(),
+    }
+}
+
+async fn reaction_to_full_reaction(ctx: &Context, reaction: &Reaction) -> Option<FullReaction> {
+    if reaction.user_id.is_none() || reaction.guild_id.is_none() {
+        return None;
+    }
+
+    // Reactions relayed from matrix are made by us
+    let user_id = reaction.user_id.unwrap();
+    if user_id == ctx.cache.current_user_id() {
+        return None;
+    }
+
+    let author = reaction.user(ctx).await;
+    if author.is_err() {
+        return None;
+    }
+    let author = author.unwrap();
+    if author.bot {
+        return None;
+    }
+
+    let mut user = author_to_user(author).await;
+    let nick = reaction.member.as_ref().and_then(|member| member.nick.clone());
+    if nick.is_some() {
+        user.display = nick.unwrap();
+    }
+
+    let guild_id = reaction.guild_id.unwrap().to_string();
+    return Some(FullReaction {
+        user: user,
+        reaction: chat_service::Message {
+            service: "discord".to_owned(),
+            server_id: guild_id.clone(),
+            room_id: reaction.channel_id.to_string(),
+            id: reaction_id(reaction.message_id, user_id, &reaction.emoji),
+        },
+        message: chat_service::Message {
+            service: "discord".to_owned(),
+            server_id: guild_id,
+            room_id: reaction.channel_id.to_string(),
+            id: reaction.message_id.to_string(),
+        },
+        emoji: reaction_emoji(&reaction.emoji),
+    });
+}
+
+// Names come from the cache, so converting a message never has to wait on discord
+pub fn resolve_mention(mention: &Mention) -> Option<(String, String)> {
+    let ctx = (*(CONTEXT.lock().unwrap())).clone();
+    if ctx.is_none() {
+        return None;
+    }
+    let cache = ctx.unwrap().cache;
+
+    match mention {
+        Mention::User(id) => {
+            let user = cache.user(UserId(id.parse::<u64>().ok()?))?;
+            return Some(matrix::relay::puppet_pill(id, &user.name));
+        }
+        Mention::Role(id) => {
+            let role_id = RoleId(id.parse::<u64>().ok()?);
+            let role = cache.guilds().iter().find_map(|guild_id| cache.role(*guild_id, role_id))?;
+            let name = format!("@{}", role.name);
+            return Some((name.clone(), format::escape_html(&name)));
+        }
+        Mention::Channel(id) => {
+            let channel = cache.guild_channel(ChannelId(id.parse::<u64>().ok()?))?;
+            let name = format!("#{}", channel.name);
+
+            // Bridged channels link to their matrix room instead
+            let room = rooms::by_discord(id);
+            let link = match room {
+                Some(room) => format!("https://matrix.to/#/{}", room.matrix),
+                None => format!("https://discord.com/channels/{}/{}", channel.guild_id, channel.id),
+            };
+            return Some((name.clone(), format!("<a href=\"{}\">{}</a>", format::escape_html(&link), format::escape_html(&name))));
+        }
+        Mention::Emoji { .. } => return None,
+    }
+}
+
+// The context is only set once the bot has connected
+pub fn context() -> RelayResult<Context> {
+    let ctx = (*(CONTEXT.lock().expect("Discord context is poisoned"))).clone();
+    return ctx.ok_or(RelayError::Transport("the discord bot isn't connected yet".to_owned()));
+}
+
+pub async fn relayed_message_to_message(msg: chat_service::Message) -> RelayResult<Message> {
+    let ctx = context()?;
+    // Threads aren't listed in the guild's channels, so the message is fetched straight from the channel id
+    let channel_id = ChannelId(msg.room_id.parse::<u64>()?);
+
+    let message_id = MessageId(msg.id.parse::<u64>()?);
+    let out_msg = channel_id.message(ctx.http.clone(), message_id).await?;
+    return Ok(out_msg);
 }
 
-pub async fn relayed_message_to_message(msg: chat_service::Message) -> Option<Message> {
-    // This may or may not work...
-    let ctx = (*(CONTEXT.lock().unwrap())).clone().unwrap();
-    let guild_id = GuildId(msg.server_id.parse::<u64>().unwrap());
-    let channel_id = ChannelId(msg.room_id.parse::<u64>().unwrap());
-    let channels = guild_id.channels(ctx.http.clone()).await;
-    let channel = channels.as_ref().unwrap().get(&channel_id).unwrap();
+pub async fn channel_name(channel_id: &str) -> String {
+    let ctx = context();
+    let channel_id = channel_id.parse::<u64>();
+    if ctx.is_err() || channel_id.is_err() {
+        return "thread".to_owned();
+    }
+    let name = ChannelId(channel_id.unwrap()).name(ctx.unwrap().cache.clone()).await;
+    return name.unwrap_or("thread".to_owned());
+}
+
+// Lets the sender know their message didn't make it to matrix. Bots can't send ephemeral messages
+// outside of interactions, so the notice removes itself after a while instead
+pub async fn notify_failure(source: &chat_service::Message, err: &RelayError) {
+    if !config().failure_notices.unwrap_or(true) {
+        return;
+    }
 
-    let message_id = MessageId(msg.id.parse::<u64>().unwrap());
-    let out_msg = channel.message(ctx.http.clone(), message_id).await;
-    if out_msg.is_ok() {
-        return Some(out_msg.unwrap());
+    let res = send_failure_notice(source, err).await;
+    if res.is_err() {
+        println!("Failed to send failure notice for {}: {}", source.id, res.err().unwrap());
+    }
+}
+
+async fn send_failure_notice(source: &chat_service::Message, err: &RelayError) -> RelayResult<()> {
+    let ctx = context()?;
+    let channel_id = ChannelId(source.room_id.parse::<u64>()?);
+    let message_id = MessageId(source.id.parse::<u64>()?);
+    let notice = channel_id
+        .send_message(ctx.http.clone(), |m| {
+            m.content(format!("This message couldn't be delivered to Matrix ({})", err))
+                .reference_message((channel_id, message_id))
+                .allowed_mentions(|mentions| mentions.replied_user(false))
+        })
+        .await?;
+
+    let http = ctx.http.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(FAILURE_NOTICE_LIFETIME).await;
+        notice.delete(http).await.ok();
+    });
+    return Ok(());
+}
+
+// Finds the bridged room of a channel. Threads under a bridged channel belong to it too, in which case the thread is returned as well
+pub async fn find_room(ctx: &Context, channel_id: ChannelId) -> Option<(Entry, Option<ChannelId>)> {
+    let room = rooms::by_discord(&channel_id.to_string());
+    if room.is_some() {
+        return Some((room.unwrap(), None));
+    }
+
+    let channel = channel_id.to_channel(ctx).await;
+    if let Ok(Channel::Guild(channel)) = channel {
+        let is_thread = matches!(
+            channel.kind,
+            ChannelType::PublicThread | ChannelType::PrivateThread | ChannelType::NewsThread
+        );
+        if is_thread && channel.parent_id.is_some() {
+            let parent = channel.parent_id.unwrap().to_string();
+            let room = rooms::by_discord(&parent);
+            if room.is_some() {
+                return Some((room.unwrap(), Some(channel_id)));
+            }
+        }
     }
     return None;
 }
@@ -98,66 +443,147 @@ impl EventHandler for Handler {
     // Event handlers are dispatched through a threadpool, and so multiple
     // events can be dispatched simultaneously.
     async fn message(&self, ctx: Context, msg: Message) {
+        let ticket = sequencer::ticket("discord", &msg.channel_id.to_string());
         println!("{} {} {}", msg.content, msg.id, msg.author.bot);
         if msg.author.bot {
             return;
         }
 
-        let mut attach_text = "".to_owned();
-        for attach in &msg.attachments {
-            attach_text.push_str(format!(" {}", attach.proxy_url.clone()).as_str());
-        }
+        let relay_msg = bridged_message(&ctx, msg).await;
+        if relay_msg.is_some() {
+            let relay_msg = relay_msg.unwrap();
+
+            ticket.turn().await;
+            if relay_msg.content != "" {
+                outbox::enqueue_or_log(&self.store, Delivery::MatrixMessage { message: relay_msg.clone() }).await;
+            }
 
-        let room = CONFIG.room.iter().find(|room| room.discord == msg.channel_id.to_string());
-        if room.is_some() {
-            let mut relay_msg = message_to_full_message(msg).await;
-            relay_msg.content = format!("{}{}", relay_msg.content.clone(), attach_text.clone());
-            let relayed = matrix::relay::relay_message(relay_msg.clone()).await;
-                
-            chat_service::create_message(relay_msg.message, relayed);
+            // Each attachment becomes its own matrix event, all of them are stored against the one discord message
+            for attach in relay_msg.attachments.iter() {
+                outbox::enqueue_or_log(&self.store, Delivery::MatrixAttachment { message: relay_msg.clone(), attachment: attach.clone() }).await;
+            }
         }
     }
 
     async fn message_delete(
         &self,
-        _ctx: Context,
+        ctx: Context,
         channel_id: ChannelId,
         deleted_message_id: MessageId,
         guild_id: Option<GuildId>,
     ) {
+        if guild_id.is_none() || find_room(&ctx, channel_id).await.is_none() {
+            return;
+        }
         let msg = chat_service::Message {
             service: "discord".to_owned(),
             server_id: guild_id.unwrap().to_string(),
             room_id: channel_id.to_string(),
             id: deleted_message_id.to_string(),
         };
-        
-        matrix::relay::delete_message(msg.clone()).await;
-        chat_service::delete_message(msg.clone());
+        let (deleted_by, reason) = find_deletion(&ctx, guild_id.unwrap(), channel_id, false).await;
+
+        // The ticket is only taken once the audit log was read, so the wait for it doesn't hold up the rest of the
+        // channel. Whatever came before the deletion still goes first, a deleted message has nothing after it
+        let ticket = sequencer::ticket("discord", &channel_id.to_string());
+        ticket.turn().await;
+        outbox::enqueue_or_log(&self.store, Delivery::MatrixDelete { message: msg, deleted_by: deleted_by, reason: reason }).await;
+    }
+
+    async fn message_delete_bulk(
+        &self,
+        ctx: Context,
+        channel_id: ChannelId,
+        multiple_deleted_messages_ids: Vec<MessageId>,
+        guild_id: Option<GuildId>,
+    ) {
+        if guild_id.is_none() || find_room(&ctx, channel_id).await.is_none() {
+            return;
+        }
+        let (deleted_by, reason) = find_deletion(&ctx, guild_id.unwrap(), channel_id, true).await;
+
+        // Taken after the audit log, like message_delete
+        let ticket = sequencer::ticket("discord", &channel_id.to_string());
+        ticket.turn().await;
+        for deleted_message_id in multiple_deleted_messages_ids {
+            let msg = chat_service::Message {
+                service: "discord".to_owned(),
+                server_id: guild_id.unwrap().to_string(),
+                room_id: channel_id.to_string(),
+                id: deleted_message_id.to_string(),
+            };
+            outbox::enqueue_or_log(
+                &self.store,
+                Delivery::MatrixDelete { message: msg, deleted_by: deleted_by.clone(), reason: reason.clone() },
+            )
+            .await;
+        }
+    }
+
+    async fn reaction_add(&self, ctx: Context, add_reaction: Reaction) {
+        let ticket = sequencer::ticket("discord", &add_reaction.channel_id.to_string());
+        let room = find_room(&ctx, add_reaction.channel_id).await;
+        if room.is_none() {
+            return;
+        }
+
+        let reaction = reaction_to_full_reaction(&ctx, &add_reaction).await;
+        if reaction.is_none() {
+            return;
+        }
+        let reaction = reaction.unwrap();
+
+        ticket.turn().await;
+        outbox::enqueue_or_log(&self.store, Delivery::MatrixReaction { reaction: reaction }).await;
+    }
+
+    async fn reaction_remove(&self, ctx: Context, removed_reaction: Reaction) {
+        let ticket = sequencer::ticket("discord", &removed_reaction.channel_id.to_string());
+        let room = find_room(&ctx, removed_reaction.channel_id).await;
+        if room.is_none() {
+            return;
+        }
+
+        let reaction = reaction_to_full_reaction(&ctx, &removed_reaction).await;
+        if reaction.is_none() {
+            return;
+        }
+        let reaction = reaction.unwrap();
+
+        ticket.turn().await;
+        outbox::enqueue_or_log(&self.store, Delivery::MatrixDeleteReaction { reaction: reaction.reaction }).await;
     }
 
     async fn message_update(
         &self,
-        _ctx: Context,
-        old_if_available: Option<Message>,
+        ctx: Context,
+        _old_if_available: Option<Message>,
         new: Option<Message>,
         event: MessageUpdateEvent,
     ) {
-        let relay_msg = chat_service::Message {
-            service: "discord".to_owned(),
-            id: event.id.to_string(),
-            room_id: event.channel_id.to_string(),
-            server_id: event.guild_id.unwrap().to_string(),
-        };
+        let ticket = sequencer::ticket("discord", &event.channel_id.to_string());
+        if !is_edit(&event) {
+            return;
+        }
 
+        // The update only has the fields that changed, the whole message is needed to keep its reply, mentions and attachments
+        let msg = if new.is_some() { Ok(new.unwrap()) } else { event.channel_id.message(&ctx.http, event.id).await };
+        if msg.is_err() {
+            println!("Failed to fetch edited message {}: {}", event.id, msg.err().unwrap());
+            return;
+        }
+        let mut msg = msg.unwrap();
+        if msg.author.bot {
+            return;
+        }
+        // Fetched messages don't have their guild id set
+        msg.guild_id = event.guild_id;
 
-        let relay_msg = chat_service::FullMessage {
-            content: event.content.unwrap().clone(),
-            user: author_to_user(event.author.unwrap()).await,
-            message: relay_msg,
-            reply: None
-        };
-        matrix::relay::edit_message(relay_msg).await;
+        let relay_msg = bridged_message(&ctx, msg).await;
+        if relay_msg.is_some() {
+            ticket.turn().await;
+            outbox::enqueue_or_log(&self.store, Delivery::MatrixEdit { message: relay_msg.unwrap() }).await;
+        }
     }
 
     // Set a handler to be called on the `ready` event. This is called when a
@@ -169,30 +595,73 @@ impl EventHandler for Handler {
     async fn ready(&self, ctx: Context, ready: Ready) {
         (*(CONTEXT.lock().unwrap())) = Some(ctx.clone());
         println!("{} is connected!", ready.user.name);
+        commands::register(&ctx).await;
     }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        if let Interaction::ApplicationCommand(command) = interaction {
+            commands::run_command(&ctx, &self.store, command).await;
+        }
+    }
+}
+
+/// Whether an update is a guild message being edited. Discord also sends updates when it unfurls links into embeds,
+/// only actual edits have an edit timestamp
+pub fn is_edit(event: &MessageUpdateEvent) -> bool {
+    return event.guild_id.is_some() && event.edited_timestamp.is_some();
+}
+
+/// Whether discord takes a token, by asking who it belongs to
+pub async fn check_token(token: &str) -> RelayResult<()> {
+    serenity::http::Http::new(token).get_current_user().await?;
+    return Ok(());
+}
+
+/// Connects again with the token in the current config
+pub fn reconnect() {
+    RECONNECT.notify_one();
 }
 
-pub async fn start_bot() {
-    // Configure the client with your Discord bot token in the environment.
-    //let token = env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
-    let token = CONFIG.discord_token.clone();
+pub async fn start_bot(store: Store) {
     // Set gateway intents, which decides what events the bot will be notified about
-    let intents = GatewayIntents::GUILD_MESSAGES
+    let intents = GatewayIntents::GUILDS
+        | GatewayIntents::GUILD_MESSAGES
+        | GatewayIntents::GUILD_MESSAGE_REACTIONS
         | GatewayIntents::DIRECT_MESSAGES
         | GatewayIntents::MESSAGE_CONTENT;
 
-    // Create a new instance of the Client, logging in as a bot. This will
-    // automatically prepend your bot token with "Bot ", which is a requirement
-    // by Discord for bot users.
-    let mut client =
-        Client::builder(&token, intents).event_handler(Handler).await.expect("Err creating client");
-
+    // A new client is made with the new token whenever it changes
+    loop {
+        // Create a new instance of the Client, logging in as a bot. This will
+        // automatically prepend your bot token with "Bot ", which is a requirement
+        // by Discord for bot users.
+        let token = config().discord_token.clone();
+        let client = Client::builder(&token, intents).event_handler(Handler { store: store.clone() }).await;
+        // The matrix side keeps running, a reload with a working token starts this again
+        if client.is_err() {
+            println!("Failed to create the discord client: {:?}", client.err().unwrap());
+            RECONNECT.notified().await;
+            continue;
+        }
+        let mut client = client.unwrap();
+        let shard_manager = client.shard_manager.clone();
 
-    // Finally, start a single shard, and start listening to events.
-    //
-    // Shards will automatically attempt to reconnect, and will perform
-    // exponential backoff until it reconnects.
-    if let Err(why) = client.start().await {
-        println!("Client error: {:?}", why);
+        // Finally, start a single shard, and start listening to events.
+        //
+        // Shards will automatically attempt to reconnect, and will perform
+        // exponential backoff until it reconnects.
+        tokio::select! {
+            res = client.start() => {
+                if let Err(why) = res {
+                    println!("Client error: {:?}", why);
+                }
+                println!("Discord is disconnected until the config is reloaded with a working discord_token");
+                RECONNECT.notified().await;
+            }
+            _ = RECONNECT.notified() => {
+                println!("The discord token changed, reconnecting");
+                shard_manager.lock().await.shutdown_all().await;
+            }
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,95 @@
+// The /bridge and /unbridge slash commands. Discord only shows them to server admins, which is checked again here.
+// /bridge only asks in the matrix room, a bridge admin there has to approve it
+
+use serenity::model::application::command::{Command, CommandOptionType};
+use serenity::model::application::interaction::application_command::ApplicationCommandInteraction;
+use serenity::model::application::interaction::InteractionResponseType;
+use serenity::model::Permissions;
+use serenity::prelude::*;
+
+use crate::{chat_service::Store, rooms};
+
+pub async fn register(ctx: &Context) {
+    let res = Command::set_global_application_commands(&ctx.http, |commands| {
+        commands
+            .create_application_command(|command| {
+                command
+                    .name("bridge")
+                    .description("Ask to bridge this channel with a Matrix room, an admin there has to approve it")
+                    .dm_permission(false)
+                    .default_member_permissions(Permissions::ADMINISTRATOR)
+                    .create_option(|option| {
+                        option
+                            .name("room")
+                            .description("ID of the Matrix room, e.g. !abc:example.com")
+                            .kind(CommandOptionType::String)
+                            .required(true)
+                    })
+            })
+            .create_application_command(|command| {
+                command
+                    .name("unbridge")
+                    .description("Stop bridging this channel")
+                    .dm_permission(false)
+                    .default_member_permissions(Permissions::ADMINISTRATOR)
+            })
+    })
+    .await;
+    if res.is_err() {
+        println!("Failed to register the slash commands: {}", res.err().unwrap());
+    }
+}
+
+pub async fn run_command(ctx: &Context, store: &Store, command: ApplicationCommandInteraction) {
+    if command.data.name != "bridge" && command.data.name != "unbridge" {
+        return;
+    }
+
+    // Bridging can take longer than the few seconds discord waits for an answer
+    let res = command
+        .create_interaction_response(&ctx.http, |response| {
+            response
+                .kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                .interaction_response_data(|message| message.ephemeral(true))
+        })
+        .await;
+    if res.is_err() {
+        println!("Failed to answer /{}: {}", command.data.name, res.err().unwrap());
+        return;
+    }
+
+    let is_admin = command
+        .member
+        .as_ref()
+        .and_then(|member| member.permissions)
+        .map(|permissions| permissions.administrator())
+        .unwrap_or(false);
+    let channel = command.channel_id.to_string();
+
+    let reply = if !is_admin {
+        "Only server admins can do that".to_owned()
+    } else if command.data.name == "bridge" {
+        let room = command
+            .data
+            .options
+            .iter()
+            .find(|option| option.name == "room")
+            .and_then(|option| option.value.as_ref())
+            .and_then(|value| value.as_str())
+            .unwrap_or("")
+            .trim()
+            .to_owned();
+        rooms::request_bridge(&channel, &room, &command.user.tag()).await
+    } else {
+        let room = rooms::by_discord(&channel);
+        match room {
+            Some(room) => rooms::unbridge_command(store, &room.matrix).await,
+            None => "This channel isn't bridged".to_owned(),
+        }
+    };
+
+    let res = command.edit_original_interaction_response(&ctx.http, |response| response.content(reply)).await;
+    if res.is_err() {
+        println!("Failed to answer /{}: {}", command.data.name, res.err().unwrap());
+    }
+}
@@ -0,0 +1,736 @@
+// Converts discord flavoured markdown into matrix html and a plain text fallback.
+// The html is built from our own tree, so only tags matrix allows are ever produced and all text is escaped.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mention {
+    User(String),
+    Role(String),
+    Channel(String),
+    Emoji { name: String, id: String, animated: bool },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Inline {
+    Text(String),
+    Bold(Vec<Inline>),
+    Italic(Vec<Inline>),
+    Underline(Vec<Inline>),
+    Strike(Vec<Inline>),
+    Spoiler(Vec<Inline>),
+    Code(String),
+    Link { text: Vec<Inline>, url: String },
+    Url(String),
+    Mention(Mention),
+    Timestamp { time: i64, style: char },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Block {
+    Lines(Vec<Vec<Inline>>),
+    CodeBlock { language: String, code: String },
+    Quote(Vec<Block>),
+    Heading(usize, Vec<Inline>),
+    List { ordered: bool, start: usize, items: Vec<Vec<Inline>> },
+}
+
+/// Returns the (plain, html) to use for a mention, or None to fall back to a generic one.
+pub type MentionResolver<'a> = &'a dyn Fn(&Mention) -> Option<(String, String)>;
+
+fn starts_with_at(chars: &[char], i: usize, pattern: &str) -> bool {
+    let mut j = i;
+    for c in pattern.chars() {
+        if j >= chars.len() || chars[j] != c {
+            return false;
+        }
+        j += 1;
+    }
+    return true;
+}
+
+// Finds the closing delimiter of a span starting at `start`, skipping escaped characters.
+// Like discord, the match is lazy but the closing delimiter can't be followed by `not_followed`.
+fn find_closing(chars: &[char], start: usize, delim: &str, not_followed: Option<char>) -> Option<usize> {
+    let len = delim.chars().count();
+    let mut j = start + 1; // Spans can't be empty
+    while j + len <= chars.len() {
+        if chars[j - 1] == '\\' && (j < 2 || chars[j - 2] != '\\') {
+            j += 1;
+            continue;
+        }
+        if starts_with_at(chars, j, delim) {
+            let next = chars.get(j + len);
+            if not_followed.is_none() || next != not_followed.as_ref() {
+                return Some(j);
+            }
+        }
+        j += 1;
+    }
+    return None;
+}
+
+fn is_word(c: char) -> bool {
+    return c.is_alphanumeric() || c == '_';
+}
+
+fn parse_angle(inner: &str) -> Option<Inline> {
+    if inner.starts_with("@&") {
+        let id = &inner[2..];
+        if id.len() > 0 && id.chars().all(|c| c.is_ascii_digit()) {
+            return Some(Inline::Mention(Mention::Role(id.to_owned())));
+        }
+        return None;
+    }
+    if inner.starts_with('@') {
+        let id = inner[1..].trim_start_matches('!');
+        if id.len() > 0 && id.chars().all(|c| c.is_ascii_digit()) {
+            return Some(Inline::Mention(Mention::User(id.to_owned())));
+        }
+        return None;
+    }
+    if inner.starts_with('#') {
+        let id = &inner[1..];
+        if id.len() > 0 && id.chars().all(|c| c.is_ascii_digit()) {
+            return Some(Inline::Mention(Mention::Channel(id.to_owned())));
+        }
+        return None;
+    }
+    if inner.starts_with(':') || inner.starts_with("a:") {
+        let animated = inner.starts_with('a');
+        let parts = inner.trim_start_matches('a').trim_start_matches(':').split(':').collect::<Vec<&str>>();
+        if parts.len() == 2 && parts[0].len() > 0 && parts[1].len() > 0 && parts[1].chars().all(|c| c.is_ascii_digit()) {
+            return Some(Inline::Mention(Mention::Emoji {
+                name: parts[0].to_owned(),
+                id: parts[1].to_owned(),
+                animated: animated,
+            }));
+        }
+        return None;
+    }
+    if inner.starts_with("t:") {
+        let parts = inner[2..].split(':').collect::<Vec<&str>>();
+        let time = parts[0].parse::<i64>();
+        if time.is_err() || parts.len() > 2 {
+            return None;
+        }
+        let mut style = 'f';
+        if parts.len() == 2 {
+            if parts[1].len() != 1 || !"tTdDfFR".contains(parts[1]) {
+                return None;
+            }
+            style = parts[1].chars().next().unwrap();
+        }
+        return Some(Inline::Timestamp { time: time.unwrap(), style: style });
+    }
+    // <https://...> is a link with the embed suppressed
+    if inner.starts_with("http://") || inner.starts_with("https://") {
+        if !inner.contains(char::is_whitespace) {
+            return Some(Inline::Url(inner.to_owned()));
+        }
+    }
+    return None;
+}
+
+fn push_text(out: &mut Vec<Inline>, text: &str) {
+    if let Some(Inline::Text(last)) = out.last_mut() {
+        last.push_str(text);
+        return;
+    }
+    out.push(Inline::Text(text.to_owned()));
+}
+
+fn parse_inline(text: &str) -> Vec<Inline> {
+    let chars = text.chars().collect::<Vec<char>>();
+    let mut out: Vec<Inline> = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let rest: String = chars[i..].iter().collect();
+
+        // Escaped formatting characters are shown as is
+        if c == '\\' && i + 1 < chars.len() && chars[i + 1].is_ascii_punctuation() {
+            push_text(&mut out, &chars[i + 1].to_string());
+            i += 2;
+            continue;
+        }
+
+        if c == '`' {
+            let mut ticks = 0;
+            while i + ticks < chars.len() && chars[i + ticks] == '`' {
+                ticks += 1;
+            }
+            let delim = "`".repeat(ticks);
+            let mut j = i + ticks;
+            let mut end = None;
+            while j + ticks <= chars.len() {
+                if starts_with_at(&chars, j, &delim) && chars.get(j + ticks) != Some(&'`') && j > i + ticks {
+                    end = Some(j);
+                    break;
+                }
+                j += 1;
+            }
+            if end.is_some() {
+                let code: String = chars[i + ticks..end.unwrap()].iter().collect();
+                out.push(Inline::Code(code.trim().to_owned()));
+                i = end.unwrap() + ticks;
+            } else {
+                push_text(&mut out, &delim);
+                i += ticks;
+            }
+            continue;
+        }
+
+        if c == '<' {
+            let end = chars[i..].iter().position(|c| *c == '>');
+            if end.is_some() {
+                let inner: String = chars[i + 1..i + end.unwrap()].iter().collect();
+                let parsed = parse_angle(&inner);
+                if parsed.is_some() {
+                    out.push(parsed.unwrap());
+                    i += end.unwrap() + 1;
+                    continue;
+                }
+            }
+        }
+
+        if c == '[' {
+            let close = find_closing(&chars, i, "](", None);
+            if close.is_some() {
+                let close = close.unwrap();
+                let url_end = chars[close + 2..].iter().position(|c| *c == ')');
+                if url_end.is_some() {
+                    let url: String = chars[close + 2..close + 2 + url_end.unwrap()].iter().collect();
+                    let url = url.trim().trim_start_matches('<').trim_end_matches('>').to_owned();
+                    if (url.starts_with("http://") || url.starts_with("https://")) && !url.contains(' ') {
+                        let label: String = chars[i + 1..close].iter().collect();
+                        out.push(Inline::Link { text: parse_inline(&label), url: url });
+                        i = close + 2 + url_end.unwrap() + 1;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        if rest.starts_with("http://") || rest.starts_with("https://") {
+            let mut end = i;
+            while end < chars.len() && !chars[end].is_whitespace() && chars[end] != '<' {
+                end += 1;
+            }
+            // Trailing punctuation is almost never part of the link
+            while end > i && ".,:;\"'!?)]".contains(chars[end - 1]) {
+                end -= 1;
+            }
+            out.push(Inline::Url(chars[i..end].iter().collect()));
+            i = end;
+            continue;
+        }
+
+        let spans: [(&str, Option<char>); 4] = [("**", Some('*')), ("__", Some('_')), ("~~", None), ("||", None)];
+        let mut matched = false;
+        for (delim, not_followed) in spans.iter() {
+            if !rest.starts_with(delim) {
+                continue;
+            }
+            let close = find_closing(&chars, i + 2, delim, *not_followed);
+            if close.is_none() {
+                continue;
+            }
+            let close = close.unwrap();
+            let inner: String = chars[i + 2..close].iter().collect();
+            let children = parse_inline(&inner);
+            out.push(match *delim {
+                "**" => Inline::Bold(children),
+                "__" => Inline::Underline(children),
+                "~~" => Inline::Strike(children),
+                _ => Inline::Spoiler(children),
+            });
+            i = close + 2;
+            matched = true;
+            break;
+        }
+        if matched {
+            continue;
+        }
+
+        if c == '*' && i + 1 < chars.len() && !chars[i + 1].is_whitespace() {
+            let mut close = find_closing(&chars, i + 1, "*", None);
+            // The closing star can't come after a space
+            while close.is_some() && chars[close.unwrap() - 1].is_whitespace() {
+                close = find_closing(&chars, close.unwrap(), "*", None);
+            }
+            if close.is_some() {
+                let inner: String = chars[i + 1..close.unwrap()].iter().collect();
+                out.push(Inline::Italic(parse_inline(&inner)));
+                i = close.unwrap() + 1;
+                continue;
+            }
+        }
+
+        // Underscores only count at word boundaries, so snake_case stays as it is
+        if c == '_' && (i == 0 || !is_word(chars[i - 1])) && i + 1 < chars.len() && !chars[i + 1].is_whitespace() {
+            let mut close = find_closing(&chars, i + 1, "_", None);
+            while close.is_some() && chars.get(close.unwrap() + 1).map_or(false, |c| is_word(*c)) {
+                close = find_closing(&chars, close.unwrap(), "_", None);
+            }
+            if close.is_some() {
+                let inner: String = chars[i + 1..close.unwrap()].iter().collect();
+                out.push(Inline::Italic(parse_inline(&inner)));
+                i = close.unwrap() + 1;
+                continue;
+            }
+        }
+
+        push_text(&mut out, &c.to_string());
+        i += 1;
+    }
+    return out;
+}
+
+fn list_item(line: &str) -> Option<(bool, usize, &str)> {
+    if line.starts_with("- ") || line.starts_with("* ") {
+        return Some((false, 1, &line[2..]));
+    }
+    let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+    if digits > 0 && digits < 10 && line[digits..].starts_with(". ") {
+        return Some((true, line[..digits].parse::<usize>().unwrap(), &line[digits + 2..]));
+    }
+    return None;
+}
+
+fn parse_lines(text: &str, out: &mut Vec<Block>) {
+    let lines = text.split('\n').collect::<Vec<&str>>();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+
+        // >>> quotes everything after it
+        if line.starts_with(">>> ") || line == ">>>" {
+            let mut quoted = vec![line.trim_start_matches(">>>").trim_start_matches(' ')];
+            quoted.extend_from_slice(&lines[i + 1..]);
+            let mut inner = Vec::new();
+            parse_lines(&quoted.join("\n"), &mut inner);
+            out.push(Block::Quote(inner));
+            return;
+        }
+
+        if line.starts_with("> ") || line == ">" {
+            let mut quoted = Vec::new();
+            while i < lines.len() && (lines[i].starts_with("> ") || lines[i] == ">") {
+                quoted.push(lines[i].trim_start_matches('>').trim_start_matches(' '));
+                i += 1;
+            }
+            let mut inner = Vec::new();
+            parse_lines(&quoted.join("\n"), &mut inner);
+            out.push(Block::Quote(inner));
+            continue;
+        }
+
+        let hashes = line.chars().take_while(|c| *c == '#').count();
+        if hashes >= 1 && hashes <= 3 && line[hashes..].starts_with(' ') && line[hashes..].trim() != "" {
+            out.push(Block::Heading(hashes, parse_inline(line[hashes..].trim())));
+            i += 1;
+            continue;
+        }
+
+        let item = list_item(line);
+        if item.is_some() {
+            let (ordered, start, _) = item.unwrap();
+            let mut items = Vec::new();
+            while i < lines.len() {
+                let next = list_item(lines[i]);
+                if next.is_none() || next.unwrap().0 != ordered {
+                    break;
+                }
+                items.push(parse_inline(next.unwrap().2));
+                i += 1;
+            }
+            out.push(Block::List { ordered: ordered, start: start, items: items });
+            continue;
+        }
+
+        // Plain lines are grouped so they can be joined with line breaks
+        let inline = parse_inline(line);
+        if let Some(Block::Lines(group)) = out.last_mut() {
+            group.push(inline);
+        } else {
+            out.push(Block::Lines(vec![inline]));
+        }
+        i += 1;
+    }
+}
+
+fn parse(text: &str) -> Vec<Block> {
+    let mut out = Vec::new();
+    let mut rest = text;
+
+    // Code blocks are found first, nothing inside them is formatted
+    while let Some(start) = rest.find("```") {
+        let after = &rest[start + 3..];
+        let end = after.find("```");
+        if end.is_none() || after[..end.unwrap()].trim() == "" {
+            break;
+        }
+        let end = end.unwrap();
+
+        let before = rest[..start].trim_end_matches('\n');
+        if before != "" {
+            parse_lines(before, &mut out);
+        }
+
+        let mut code = &after[..end];
+        let mut language = "";
+        let first_line_end = code.find('\n');
+        if first_line_end.is_some() {
+            let first_line = &code[..first_line_end.unwrap()];
+            if first_line.len() > 0 && !first_line.contains(char::is_whitespace) {
+                language = first_line;
+                code = &code[first_line_end.unwrap() + 1..];
+            } else if first_line.trim() == "" {
+                code = &code[first_line_end.unwrap() + 1..];
+            }
+        }
+        out.push(Block::CodeBlock {
+            language: language.to_owned(),
+            code: code.trim_end_matches('\n').to_owned(),
+        });
+
+        rest = after[end + 3..].trim_start_matches('\n');
+    }
+    if rest != "" {
+        parse_lines(rest, &mut out);
+    }
+    return out;
+}
+
+pub fn escape_html(text: &str) -> String {
+    return text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;");
+}
+
+// Days since 1970-01-01 to (year, month, day), from Howard Hinnant's date algorithms
+fn civil_from_days(days: i64) -> (i64, usize, i64) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    return (year, month as usize, day);
+}
+
+fn format_relative(time: i64, now: i64) -> String {
+    let diff = (time - now).abs();
+    let units: [(i64, &str); 6] = [
+        (365 * 24 * 3600, "year"),
+        (30 * 24 * 3600, "month"),
+        (24 * 3600, "day"),
+        (3600, "hour"),
+        (60, "minute"),
+        (1, "second"),
+    ];
+    let (size, name) = units.iter().find(|(size, _)| diff >= *size).unwrap_or(&(1, "second"));
+    let amount = diff / size;
+    let plural = if amount == 1 { "" } else { "s" };
+    if time >= now {
+        return format!("in {} {}{}", amount, name, plural);
+    }
+    return format!("{} {}{} ago", amount, name, plural);
+}
+
+/// Formats a discord timestamp the way discord would, in UTC since we don't know the reader's timezone.
+pub fn format_timestamp(time: i64, style: char, now: i64) -> String {
+    const MONTHS: [&str; 12] = [
+        "January", "February", "March", "April", "May", "June",
+        "July", "August", "September", "October", "November", "December",
+    ];
+    const WEEKDAYS: [&str; 7] = ["Thursday", "Friday", "Saturday", "Sunday", "Monday", "Tuesday", "Wednesday"];
+
+    let days = time.div_euclid(86400);
+    let seconds = time.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let (hour, minute, second) = (seconds / 3600, (seconds / 60) % 60, seconds % 60);
+    let weekday = WEEKDAYS[days.rem_euclid(7) as usize];
+    let month_name = MONTHS[month - 1];
+
+    return match style {
+        't' => format!("{:02}:{:02} UTC", hour, minute),
+        'T' => format!("{:02}:{:02}:{:02} UTC", hour, minute, second),
+        'd' => format!("{:02}/{:02}/{}", day, month, year),
+        'D' => format!("{} {} {}", day, month_name, year),
+        'F' => format!("{}, {} {} {} {:02}:{:02} UTC", weekday, day, month_name, year, hour, minute),
+        'R' => format_relative(time, now),
+        _ => format!("{} {} {} {:02}:{:02} UTC", day, month_name, year, hour, minute),
+    };
+}
+
+fn default_mention(mention: &Mention) -> (String, String) {
+    let plain = match mention {
+        Mention::User(id) => format!("@{}", id),
+        Mention::Role(id) => format!("@&{}", id),
+        Mention::Channel(id) => format!("#{}", id),
+        Mention::Emoji { name, .. } => format!(":{}:", name),
+    };
+    let html = escape_html(&plain);
+    return (plain, html);
+}
+
+struct Renderer<'a> {
+    resolve: MentionResolver<'a>,
+    now: i64,
+}
+
+impl<'a> Renderer<'a> {
+    fn mention(&self, mention: &Mention) -> (String, String) {
+        return (self.resolve)(mention).unwrap_or_else(|| default_mention(mention));
+    }
+
+    fn inline_html(&self, nodes: &Vec<Inline>) -> String {
+        let mut out = "".to_owned();
+        for node in nodes {
+            out.push_str(&match node {
+                Inline::Text(text) => escape_html(text),
+                Inline::Bold(children) => format!("<strong>{}</strong>", self.inline_html(children)),
+                Inline::Italic(children) => format!("<em>{}</em>", self.inline_html(children)),
+                Inline::Underline(children) => format!("<u>{}</u>", self.inline_html(children)),
+                Inline::Strike(children) => format!("<del>{}</del>", self.inline_html(children)),
+                Inline::Spoiler(children) => format!("<span data-mx-spoiler>{}</span>", self.inline_html(children)),
+                Inline::Code(code) => format!("<code>{}</code>", escape_html(code)),
+                Inline::Link { text, url } => format!("<a href=\"{}\">{}</a>", escape_html(url), self.inline_html(text)),
+                Inline::Url(url) => format!("<a href=\"{}\">{}</a>", escape_html(url), escape_html(url)),
+                Inline::Mention(mention) => self.mention(mention).1,
+                Inline::Timestamp { time, style } => escape_html(&format_timestamp(*time, *style, self.now)),
+            });
+        }
+        return out;
+    }
+
+    fn inline_plain(&self, nodes: &Vec<Inline>) -> String {
+        let mut out = "".to_owned();
+        for node in nodes {
+            out.push_str(&match node {
+                Inline::Text(text) => text.clone(),
+                Inline::Bold(children)
+                | Inline::Italic(children)
+                | Inline::Underline(children)
+                | Inline::Strike(children) => self.inline_plain(children),
+                Inline::Spoiler(children) => format!("||{}||", self.inline_plain(children)),
+                Inline::Code(code) => format!("`{}`", code),
+                Inline::Link { text, url } => {
+                    let text = self.inline_plain(text);
+                    if text == *url {
+                        url.clone()
+                    } else {
+                        format!("{} ({})", text, url)
+                    }
+                }
+                Inline::Url(url) => url.clone(),
+                Inline::Mention(mention) => self.mention(mention).0,
+                Inline::Timestamp { time, style } => format_timestamp(*time, *style, self.now),
+            });
+        }
+        return out;
+    }
+
+    fn html(&self, blocks: &Vec<Block>) -> String {
+        let mut out = "".to_owned();
+        for (i, block) in blocks.iter().enumerate() {
+            let rendered = match block {
+                Block::Lines(lines) => lines
+                    .iter()
+                    .map(|line| self.inline_html(line))
+                    .collect::<Vec<String>>()
+                    .join("<br>"),
+                Block::CodeBlock { language, code } => {
+                    if language == "" {
+                        format!("<pre><code>{}</code></pre>", escape_html(code))
+                    } else {
+                        format!("<pre><code class=\"language-{}\">{}</code></pre>", escape_html(language), escape_html(code))
+                    }
+                }
+                Block::Quote(inner) => format!("<blockquote>{}</blockquote>", self.html(inner)),
+                Block::Heading(level, inline) => format!("<h{}>{}</h{}>", level, self.inline_html(inline), level),
+                Block::List { ordered, start, items } => {
+                    let items = items
+                        .iter()
+                        .map(|item| format!("<li>{}</li>", self.inline_html(item)))
+                        .collect::<Vec<String>>()
+                        .join("");
+                    if !ordered {
+                        format!("<ul>{}</ul>", items)
+                    } else if *start != 1 {
+                        format!("<ol start=\"{}\">{}</ol>", start, items)
+                    } else {
+                        format!("<ol>{}</ol>", items)
+                    }
+                }
+            };
+
+            // Only runs of plain lines need a break between them, everything else is already a block
+            if i > 0 && matches!(block, Block::Lines(_)) && matches!(blocks[i - 1], Block::Lines(_)) {
+                out.push_str("<br>");
+            }
+            out.push_str(&rendered);
+        }
+        return out;
+    }
+
+    fn plain(&self, blocks: &Vec<Block>) -> String {
+        let mut out: Vec<String> = Vec::new();
+        for block in blocks {
+            out.push(match block {
+                Block::Lines(lines) => lines
+                    .iter()
+                    .map(|line| self.inline_plain(line))
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+                Block::CodeBlock { language, code } => format!("```{}\n{}\n```", language, code),
+                Block::Quote(inner) => self
+                    .plain(inner)
+                    .lines()
+                    .map(|line| format!("> {}", line))
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+                Block::Heading(_, inline) => self.inline_plain(inline),
+                Block::List { ordered, start, items } => items
+                    .iter()
+                    .enumerate()
+                    .map(|(i, item)| {
+                        if *ordered {
+                            format!("{}. {}", start + i, self.inline_plain(item))
+                        } else {
+                            format!("- {}", self.inline_plain(item))
+                        }
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+            });
+        }
+        return out.join("\n");
+    }
+}
+
+/// Converts discord markdown into a (plain, html) pair for a matrix message.
+pub fn discord_to_matrix(text: &str, resolve: MentionResolver) -> (String, String) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
+    return discord_to_matrix_at(text, resolve, now);
+}
+
+// Same as discord_to_matrix, with the time relative timestamps are shown against
+pub fn discord_to_matrix_at(text: &str, resolve: MentionResolver, now: i64) -> (String, String) {
+    let blocks = parse(text);
+    let renderer = Renderer { resolve: resolve, now: now };
+    return (renderer.plain(&blocks), renderer.html(&blocks));
+}
+
+// Fences longer than this (e.g. ```some-very-long-language) are reopened without the language
+const MAX_REOPENED_FENCE: usize = 16;
+// Room left in each chunk for reopening and closing a code fence
+const FENCE_MARGIN: usize = MAX_REOPENED_FENCE + 5;
+
+// Cuts a line that won't fit in a message on its own, at whitespace where possible
+fn split_line(line: &str, limit: usize) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut rest: Vec<char> = line.chars().collect();
+    while rest.len() > limit {
+        let cut = rest[..limit].iter().rposition(|c| c.is_whitespace()).filter(|&i| i > 0).unwrap_or(limit);
+        out.push(rest[..cut].iter().collect::<String>().trim_end().to_owned());
+        rest = rest[cut..].to_vec();
+        while rest.first().map_or(false, |c| c.is_whitespace()) {
+            rest.remove(0);
+        }
+    }
+    out.push(rest.into_iter().collect());
+    return out;
+}
+
+/// Splits a message into chunks of at most `limit` characters.
+/// Paragraph breaks are preferred, then line breaks, and code blocks are only split when they can't fit in a chunk
+/// of their own, in which case the fence is closed and reopened in the next chunk.
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+    if text.chars().count() <= limit {
+        return vec![text.to_owned()];
+    }
+
+    let line_limit = if limit > FENCE_MARGIN * 2 { limit - FENCE_MARGIN } else { limit };
+    let lines: Vec<String> = text.split('\n').flat_map(|line| split_line(line, line_limit)).collect();
+
+    // The fence each line leaves open, if any
+    let mut fences: Vec<Option<String>> = Vec::new();
+    let mut fence: Option<String> = None;
+    for line in lines.iter() {
+        // Like discord, any unpaired ``` opens or closes a code block, even in the middle of a line
+        if line.matches("```").count() % 2 == 1 {
+            if fence.is_none() {
+                let language = line.rsplit("```").next().unwrap_or("").trim();
+                let plain = language.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '+' || c == '#');
+                if plain && language.len() + 3 <= MAX_REOPENED_FENCE {
+                    fence = Some(format!("```{}", language));
+                } else {
+                    fence = Some("```".to_owned());
+                }
+            } else {
+                fence = None;
+            }
+        }
+        fences.push(fence.clone());
+    }
+
+    let mut chunks: Vec<String> = Vec::new();
+    let mut start = 0;
+    while start < lines.len() {
+        let reopen = if start > 0 { fences[start - 1].clone() } else { None };
+
+        // Blank lines between chunks are dropped, unless they are part of a code block
+        if reopen.is_none() && lines[start].trim() == "" {
+            start += 1;
+            continue;
+        }
+
+        // Find how many lines fit, including the fence being reopened and closed
+        let mut length = reopen.as_ref().map_or(0, |opener| opener.chars().count() + 1);
+        let mut end = start;
+        while end < lines.len() {
+            let added = lines[end].chars().count() + if end > start { 1 } else { 0 };
+            let closing = if fences[end].is_some() { 4 } else { 0 };
+            if length + added + closing > limit {
+                break;
+            }
+            length += added;
+            end += 1;
+        }
+        end = end.max(start + 1);
+
+        // Break after a paragraph if we can, then after any line outside a code block
+        let mut cut = end;
+        if end < lines.len() {
+            let outside = |i: usize| fences[i - 1].is_none();
+            let paragraph = (start + 1..=end).rev().find(|&i| outside(i) && (lines[i - 1].trim() == "" || lines[i].trim() == ""));
+            let line = (start + 1..=end).rev().find(|&i| outside(i));
+            cut = paragraph.or(line).unwrap_or(end);
+        }
+
+        let mut chunk = lines[start..cut].join("\n");
+        if reopen.is_some() {
+            chunk = format!("{}\n{}", reopen.unwrap(), chunk);
+        }
+        chunk = chunk.trim_end_matches('\n').to_owned();
+        if fences[cut - 1].is_some() {
+            chunk = format!("{}\n```", chunk);
+        }
+        chunks.push(chunk);
+        start = cut;
+    }
+    return chunks;
+}
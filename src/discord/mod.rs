@@ -1,2 +1,4 @@
 pub mod bot;
+pub mod commands;
+pub mod format;
 pub mod relay;
\ No newline at end of file
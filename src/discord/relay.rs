@@ -1,11 +1,30 @@
-use crate::chat_service::{FullMessage, Message};
-use crate::{chat_service, CONFIG};
+use crate::chat_service::{FullMessage, FullReaction, Message, Store};
+use crate::error::{RelayError, RelayResult};
+use crate::{chat_service, matrix, rooms, Entry};
 use reqwest;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::fmt::format;
+use std::time::Duration;
 
-use super::bot::{CONTEXT, relayed_message_to_message};
+use ruma::MxcUri;
+use serenity::model::prelude::{Channel, ChannelId, MessageId, ReactionType};
+
+use super::format;
+use super::bot::{context, reaction_id, relayed_message_to_message};
+
+// Discord's upload limit for servers without boosts
+const DEFAULT_MAX_UPLOAD_SIZE: u64 = 10 * 1024 * 1024;
+
+// Longest message content discord accepts
+const MAX_MESSAGE_LENGTH: usize = 2000;
+
+const MAX_USERNAME_LENGTH: usize = 80;
+// Webhook usernames containing these are rejected by discord
+const BANNED_USERNAME_WORDS: [&str; 4] = ["discord", "clyde", "everyone", "here"];
+
+// Name of the webhooks the relay makes, messages still show the sender's name
+const WEBHOOK_NAME: &str = "Matrix relay";
 
 #[derive(Debug, Deserialize, Clone)]
 struct WebhookResponse {
@@ -23,115 +42,534 @@ fn sanitize(message: String) -> String
     return out;
 }
 
-pub async fn delete_message(message: Message)
+// Deleting a message that is already gone counts as done
+async fn delete_discord_message(msg: Message) -> RelayResult<()>
 {
-    let relayed_messages = chat_service::message_relays(message.clone());
-    let http = (*(CONTEXT.lock().unwrap())).as_ref().unwrap().http.clone();
-    if relayed_messages.len() > 0 {
-        for msg in relayed_messages {
-            if msg.service == "discord" {
-                let discord_msg = relayed_message_to_message(msg).await;
-                if discord_msg.is_some() {
-                    discord_msg.unwrap().delete(http.clone()).await;
-                }
-            }
+    let http = context()?.http.clone();
+    let channel_id = ChannelId(msg.room_id.parse::<u64>()?);
+    let message_id = MessageId(msg.id.parse::<u64>()?);
+    let res = channel_id.delete_message(http, message_id).await.map_err(RelayError::from);
+    if let Err(RelayError::NotFound(_)) = res {
+        return Ok(());
+    }
+    return res;
+}
+
+pub async fn delete_message(store: &Store, message: Message) -> RelayResult<()>
+{
+    let mut targets: Vec<Message> = store.message_relays(message.clone()).await?
+        .into_iter()
+        .filter(|msg| msg.service == "discord")
+        .collect();
+
+    let origin_message = store.message_origin(message.clone()).await?;
+    if origin_message.is_some() && origin_message.as_ref().unwrap().service == "discord" {
+        targets.push(origin_message.unwrap());
+    }
+
+    // Every part is tried, the first failure is returned
+    let mut result = Ok(());
+    for msg in targets {
+        let res = delete_discord_message(msg).await;
+        if res.is_err() && result.is_ok() {
+            result = res;
         }
     }
+    return result;
+}
 
-    let origin_message = chat_service::message_origin(message.clone());
-    if origin_message.is_some() && origin_message.clone().unwrap().clone().service == "discord" {
-        let discord_msg = relayed_message_to_message(origin_message.unwrap()).await;
-        if discord_msg.is_some() {
-            discord_msg.unwrap().delete(http.clone()).await;
+// Breaks up words discord doesn't allow in webhook usernames with a zero width space
+fn sanitize_username(name: &str) -> String
+{
+    let mut out = name.to_owned();
+    for word in BANNED_USERNAME_WORDS {
+        loop {
+            let lower = out.to_lowercase();
+            let found = lower.find(word);
+            // Lowercasing can change byte offsets for some characters, in which case we can't split safely
+            if found.is_none() || lower.len() != out.len() || !out.is_char_boundary(found.unwrap() + 1) {
+                break;
+            }
+            let split = found.unwrap() + 1;
+            out = format!("{}\u{200B}{}", &out[..split], &out[split..]);
         }
     }
+    return out;
+}
+
+/// Webhook username for a relayed user, `display (tag)` cut down to discord's limit
+pub fn webhook_username(display: &str, tag: &str) -> String
+{
+    let display = sanitize_username(display.trim());
+    let tag = sanitize_username(tag.trim());
+
+    let mut name = format!("{} ({})", display, tag);
+    if display == tag || display == "" {
+        name = tag.clone();
+    }
+    if name.chars().count() <= MAX_USERNAME_LENGTH {
+        return name;
+    }
+
+    // Shorten the display name first, so the tag stays intact
+    let suffix = format!("\u{2026} ({})", tag);
+    let suffix_length = suffix.chars().count();
+    if suffix_length < MAX_USERNAME_LENGTH && display != tag {
+        let display: String = display.chars().take(MAX_USERNAME_LENGTH - suffix_length).collect();
+        return format!("{}{}", display.trim_end(), suffix);
+    }
+    let name: String = name.chars().take(MAX_USERNAME_LENGTH - 1).collect();
+    return format!("{}\u{2026}", name);
 }
 
+// Turns a failed webhook response into an error, discord says how long to wait in the body of a 429
+async fn webhook_response(res: reqwest::Response) -> RelayResult<WebhookResponse>
+{
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res.json::<WebhookResponse>().await?);
+    }
+
+    let body: serde_json::Value = res.json().await.unwrap_or_default();
+    let retry_after = body["retry_after"].as_f64().map(Duration::from_secs_f64);
+    let reason = format!("webhook: {}", body["message"].as_str().unwrap_or("no message"));
+    return Err(RelayError::from_status(status.as_u16(), reason, retry_after));
+}
 
+// Files are (filename, data) pairs, they are sent as a multipart upload alongside the message
 async fn send_message_webhook(
     webhook: String,
     message: String,
     username: Option<String>,
-) -> WebhookResponse {
-    let mut params = HashMap::new();
-    params.insert("content", sanitize(message));
+    avatar_url: Option<String>,
+    files: Vec<(String, Vec<u8>)>,
+    thread_id: Option<String>,
+) -> RelayResult<WebhookResponse> {
+    // Puppet pings should notify the discord user, but roles shouldn't be pinged from matrix
+    let mut payload = serde_json::json!({
+        "content": sanitize(message),
+        "allowed_mentions": { "parse": ["users"] },
+    });
     if username.is_some() {
-        params.insert("username", username.unwrap());
+        payload["username"] = serde_json::Value::String(username.unwrap());
+    }
+    if avatar_url.is_some() {
+        payload["avatar_url"] = serde_json::Value::String(avatar_url.unwrap());
+    }
+
+    let mut form = reqwest::multipart::Form::new().text("payload_json", payload.to_string());
+    for (i, (filename, data)) in files.into_iter().enumerate() {
+        form = form.part(
+            format!("files[{}]", i),
+            reqwest::multipart::Part::bytes(data).file_name(filename),
+        );
+    }
+
+    let mut url = format!("{}?wait=1", webhook);
+    if thread_id.is_some() {
+        url = format!("{}&thread_id={}", url, thread_id.unwrap());
     }
 
     let client = reqwest::Client::new();
     let res = client
-        .post(format!("{}?wait=1", webhook))
-        .form(&params)
+        .post(url)
+        .multipart(form)
         .send()
-        .await
-        .expect("Should have sent message!")
-        .json::<WebhookResponse>()
-        .await
-        .expect("Should have parsed!");
+        .await?;
 
-    return res;
+    return webhook_response(res).await;
 }
 
 async fn edit_message_webhook(
     webhook: String,
     message_id: String,
     message: String,
-) -> WebhookResponse {
+    thread_id: Option<String>,
+) -> RelayResult<WebhookResponse> {
     let mut params = HashMap::new();
     params.insert("content", sanitize(message));
 
+    let mut url = format!("{}/messages/{}", webhook, message_id);
+    if thread_id.is_some() {
+        url = format!("{}?thread_id={}", url, thread_id.unwrap());
+    }
+
     let client = reqwest::Client::new();
     let res = client
-        .patch(format!("{}/messages/{}", webhook, message_id))
+        .patch(url)
         .form(&params)
         .send()
-        .await
-        .expect("Should have sent message!")
-        .json::<WebhookResponse>()
-        .await
-        .expect("Should have parsed!");
+        .await?;
 
-    return res;
+    return webhook_response(res).await;
+}
+
+async fn download_attachment(url: &str) -> RelayResult<(Vec<u8>, Option<String>)> {
+    if url.starts_with("mxc://") {
+        return matrix::relay::download_media(url).await;
+    }
+    return chat_service::download(url.to_owned()).await;
+}
+
+/// What is sent in place of a file that couldn't be uploaded. Matrix media is only linked when there is a public
+/// media_url to link to
+pub fn attachment_link(filename: &str, url: &str) -> String {
+    let mut link = Some(url.to_owned());
+    if url.starts_with("mxc://") {
+        link = matrix::bot::mxc_to_url(<&MxcUri>::from(url));
+    }
+    match link {
+        Some(link) => return format!("[{}](<{}>)", filename, link),
+        None => return format!("{} (couldn't be relayed)", filename),
+    }
+}
+
+/// The guild of a channel that is being bridged
+pub async fn channel_guild(channel_id: &str) -> RelayResult<String> {
+    let ctx = context()?;
+    let channel = ChannelId(channel_id.parse::<u64>()?).to_channel(&ctx).await?;
+    let channel = match channel {
+        Channel::Guild(channel) => channel,
+        _ => return Err(RelayError::Invalid(format!("{} isn't a server channel", channel_id))),
+    };
+    // Webhooks can post into threads, but not be made in one
+    if channel.thread_metadata.is_some() {
+        return Err(RelayError::Invalid(format!("{} is a thread, its channel has to be bridged instead", channel_id)));
+    }
+    return Ok(channel.guild_id.to_string());
+}
+
+fn webhook_url(id: &str, token: &str) -> String {
+    return format!("https://discord.com/api/webhooks/{}/{}", id, token);
+}
+
+/// Finds the webhook the relay made in a channel before, or makes one, and uses it for the channel from now on
+pub async fn provision_webhook(store: &Store, channel_id: &str) -> RelayResult<String> {
+    let ctx = context()?;
+    let channel = ChannelId(channel_id.parse::<u64>()?);
+    let bot_id = ctx.cache.current_user_id();
+
+    // Only webhooks made by the bot come with their token
+    let mut webhook = channel.webhooks(&ctx.http).await?.into_iter().find(|webhook| {
+        webhook.token.is_some()
+            && webhook.name.as_deref() == Some(WEBHOOK_NAME)
+            && webhook.user.as_ref().map(|user| user.id) == Some(bot_id)
+    });
+    if webhook.is_none() {
+        webhook = Some(channel.create_webhook(&ctx.http, WEBHOOK_NAME).await?);
+    }
+    let webhook = webhook.unwrap();
+    let token = webhook.token.ok_or(RelayError::Transport("discord didn't return the webhook's token".to_owned()))?;
+
+    store.set_channel_webhook(channel_id, &webhook.id.to_string(), &token).await?;
+    println!("Using webhook {} for {}", webhook.id, channel_id);
+    return Ok(webhook_url(&webhook.id.to_string(), &token));
+}
+
+// The relay's own webhook once it has one, otherwise the one in the config
+async fn room_webhook(store: &Store, room: &Entry) -> RelayResult<String> {
+    let provisioned = store.channel_webhook(&room.discord).await?;
+    if provisioned.is_some() {
+        let (id, token) = provisioned.unwrap();
+        return Ok(webhook_url(&id, &token));
+    }
+    if room.webhook.is_some() {
+        return Ok(room.webhook.clone().unwrap());
+    }
+    return provision_webhook(store, &room.discord).await;
+}
+
+// Whether a webhook call failed because someone deleted the webhook, rather than e.g. the message it edits
+async fn webhook_deleted<T>(res: &RelayResult<T>, webhook: &str) -> bool {
+    if !matches!(res, Err(RelayError::NotFound(_))) {
+        return false;
+    }
+    let check = reqwest::get(webhook).await;
+    return check.map(|check| check.status() == reqwest::StatusCode::NOT_FOUND).unwrap_or(false);
 }
 
-pub async fn relay_message(message: FullMessage) -> Message {
-    let mut out: Message = message.message.clone();
-    let mut webhook = "".to_owned();
-    let room = CONFIG
-        .room
-        .iter()
-        .find(|room| room.matrix == message.message.room_id);
+// The discord thread for a matrix thread root, started from the root's discord message the first time it is used
+async fn find_thread(store: &Store, room: &Entry, root: Message) -> RelayResult<Option<String>> {
+    let thread = store.root_thread(root.clone()).await?;
+    if thread.is_some() {
+        return Ok(Some(thread.unwrap().id));
+    }
+
+    let starter = store.message_counterpart(root.clone(), "discord").await?;
+    if starter.is_none() {
+        return Ok(None);
+    }
+    let starter = starter.unwrap();
+
+    // Discord threads can't be nested, so if the root is already in one we use that
+    if starter.room_id != room.discord {
+        return Ok(Some(starter.room_id));
+    }
+
+    let starter_msg = relayed_message_to_message(starter.clone()).await.ok();
+    let mut name: String = starter_msg
+        .map(|msg| msg.content.lines().next().unwrap_or("").to_owned())
+        .unwrap_or("".to_owned())
+        .chars()
+        .take(100)
+        .collect();
+    if name.trim() == "" {
+        name = "Thread".to_owned();
+    }
+
+    let ctx = context()?;
+    let channel_id = ChannelId(starter.room_id.parse::<u64>()?);
+    let message_id = MessageId(starter.id.parse::<u64>()?);
+    let created = channel_id
+        .create_public_thread(ctx.http.clone(), message_id, |thread| thread.name(name))
+        .await?;
+
+    let thread_id = created.id.to_string();
+    let thread = Message {
+        service: "discord".to_owned(),
+        server_id: room.discord_guild.clone(),
+        room_id: room.discord.clone(),
+        id: thread_id.clone(),
+    };
+    store.create_thread(thread, root).await?;
+    return Ok(Some(thread_id));
+}
+
+/// The chunks a message still has to send, when the first ones were sent by an earlier attempt. Each says whether it
+/// is the last one, which the files go with
+pub fn unsent_chunks(chunks: Vec<String>, sent: usize) -> Vec<(String, bool)> {
+    let last = chunks.len().saturating_sub(1);
+    return chunks.into_iter().enumerate().skip(sent).map(|(i, chunk)| (chunk, i == last)).collect();
+}
+
+// Long messages are split into several discord messages. Each part is stored as soon as it is sent, so a retry carries
+// on after the parts that made it. Returns the parts sent this time
+pub async fn relay_message(store: &Store, message: FullMessage) -> RelayResult<Vec<Message>> {
+    let room = rooms::by_matrix(&message.message.room_id);
     if room.is_none() {
-        return message.message;
+        return Ok(Vec::new());
     }
+    let room = room.unwrap();
+    let sent = store.message_relays(message.message.clone()).await?.into_iter().filter(|msg| msg.service == "discord").count();
 
-    out = Message {
+    let mut out = Message {
         service: "discord".to_owned(),
-        server_id: room.unwrap().discord_guild.to_owned(),
-        room_id: room.unwrap().discord.clone(),
+        server_id: room.discord_guild.to_owned(),
+        room_id: room.discord.clone(),
         id: message.message.id.clone(),
     };
-    webhook = room.unwrap().webhook.clone();
+    let mut webhook = room_webhook(store, &room).await?;
+    let max_upload_size = room.max_upload_size.unwrap_or(DEFAULT_MAX_UPLOAD_SIZE);
+
+    // Anything too big for discord, or that we can't fetch, is linked instead
+    let mut content = message.content.clone();
+    let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+    for attach in message.attachments.iter() {
+        let mut data: Option<Vec<u8>> = None;
+        if attach.size <= max_upload_size {
+            let download = download_attachment(&attach.url).await;
+            if download.is_err() {
+                println!("Failed to download {}, linking it instead: {}", attach.url, download.as_ref().err().unwrap());
+            }
+            data = download.ok().map(|(data, _)| data);
+        }
+
+        if data.is_some() && data.as_ref().unwrap().len() as u64 <= max_upload_size {
+            files.push((attach.filename.clone(), data.unwrap()));
+        } else {
+            content = format!("{}\n{}", content, attachment_link(&attach.filename, &attach.url)).trim_start().to_owned();
+        }
+    }
+
+    let mut thread_id: Option<String> = None;
+    if message.thread.is_some() {
+        // Without the thread the message still makes it into the channel
+        let thread = find_thread(store, &room, message.thread.clone().unwrap()).await;
+        if thread.is_err() {
+            println!("Failed to find thread for {}: {}", message.message.id, thread.as_ref().err().unwrap());
+        }
+        thread_id = thread.unwrap_or(None);
+        if thread_id.is_some() {
+            out.room_id = thread_id.clone().unwrap();
+        }
+    }
+
+    // Files go with the last chunk, so they show up after the text
+    let chunks = format::split_message(&sanitize(content), MAX_MESSAGE_LENGTH);
+    let mut files = Some(files);
+    let mut relayed: Vec<Message> = Vec::new();
+    for (chunk, last) in unsent_chunks(chunks, sent).into_iter() {
+        let chunk_files = if last { files.take().unwrap_or_default() } else { Vec::new() };
+        let send = |webhook: String| send_message_webhook(
+            webhook,
+            chunk.clone(),
+            Some(webhook_username(&message.user.display, &message.user.tag)),
+            message.user.avatar.clone(),
+            chunk_files.clone(),
+            thread_id.clone(),
+        );
+        let mut wh = send(webhook.clone()).await;
+        // A new webhook is made in its place, and the message goes through that one
+        if webhook_deleted(&wh, &webhook).await {
+            println!("The webhook of {} was deleted, making a new one", room.discord);
+            webhook = provision_webhook(store, &room.discord).await?;
+            wh = send(webhook.clone()).await;
+        }
+
+        if wh.is_err() {
+            return Err(wh.err().unwrap());
+        }
+        let mut chunk_msg = out.clone();
+        chunk_msg.id = wh.unwrap().id;
+        store.create_message(message.message.clone(), chunk_msg.clone()).await?;
+        relayed.push(chunk_msg);
+    }
+    return Ok(relayed);
+}
+
+// The new content is split the same way as relay_message, parts that are no longer needed are deleted
+// and extra parts are sent as new messages
+pub async fn edit_message(store: &Store, message: FullMessage) -> RelayResult<()> {
+    let room = rooms::by_matrix(&message.message.room_id);
+    if room.is_none() {
+        return Ok(());
+    }
+    let room = room.unwrap();
+    let mut webhook = room_webhook(store, &room).await?;
 
-    let wh = send_message_webhook(
+    let relayed_messages: Vec<Message> = store.message_relays(message.clone().message).await?
+        .into_iter()
+        .filter(|msg| msg.service == "discord")
+        .collect();
+    if relayed_messages.len() == 0 {
+        return Ok(());
+    }
+
+    // Messages in threads are stored with the thread as their room
+    let mut thread_id: Option<String> = None;
+    if relayed_messages[0].room_id != room.discord {
+        thread_id = Some(relayed_messages[0].room_id.clone());
+    }
+
+    let send = |webhook: String, chunk: String| send_message_webhook(
         webhook,
-        message.content,
-        Some(format!("{} ({})", message.user.display, message.user.tag).to_owned()),
-    )
-    .await;
-    out.id = wh.id;
-    return out;
+        chunk,
+        Some(webhook_username(&message.user.display, &message.user.tag)),
+        message.user.avatar.clone(),
+        Vec::new(),
+        thread_id.clone(),
+    );
+
+    let chunks = format::split_message(&sanitize(message.content.clone()), MAX_MESSAGE_LENGTH);
+    // Set once the webhook that sent the parts is gone, none of them can be edited after that
+    let mut orphaned = false;
+    for (i, msg) in relayed_messages.iter().enumerate() {
+        if i < chunks.len() && !orphaned {
+            let res = edit_message_webhook(webhook.clone(), msg.id.clone(), chunks[i].clone(), thread_id.clone()).await;
+            if !webhook_deleted(&res, &webhook).await {
+                res?;
+                continue;
+            }
+            println!("The webhook of {} was deleted, making a new one", room.discord);
+            webhook = provision_webhook(store, &room.discord).await?;
+            orphaned = true;
+        }
+
+        // Parts of a deleted webhook are sent again through the new one, in place of the old part
+        if i < chunks.len() {
+            let wh = send(webhook.clone(), chunks[i].clone()).await?;
+            let mut replacement = msg.clone();
+            replacement.id = wh.id;
+            store.create_message(message.message.clone(), replacement).await?;
+            store.delete_relayed_message(msg.clone()).await?;
+            let res = delete_discord_message(msg.clone()).await;
+            if res.is_err() {
+                println!("Failed to delete the old part {} of an edit: {}", msg.id, res.err().unwrap());
+            }
+            continue;
+        }
+        delete_discord_message(msg.clone()).await?;
+        store.delete_relayed_message(msg.clone()).await?;
+    }
+
+    for chunk in chunks.into_iter().skip(relayed_messages.len()) {
+        let wh = send(webhook.clone(), chunk).await?;
+        let mut relayed = relayed_messages[0].clone();
+        relayed.id = wh.id;
+        store.create_message(message.message.clone(), relayed).await?;
+    }
+    return Ok(());
 }
 
-pub async fn edit_message(message: FullMessage) {
-    let room = CONFIG.room.iter().find(|room| room.matrix == message.message.room_id);
-    let webhook = room.unwrap().webhook.clone();
+// None when the message isn't bridged or the emoji only exists on matrix
+pub async fn relay_reaction(store: &Store, reaction: FullReaction) -> RelayResult<Option<Message>> {
+    let target = store.message_counterpart(reaction.message.clone(), "discord").await?;
+    if target.is_none() {
+        return Ok(None);
+    }
+    let target = target.unwrap();
+
+    let emoji = ReactionType::try_from(reaction.emoji.as_str());
+    if emoji.is_err() {
+        return Ok(None);
+    }
+    let emoji = emoji.unwrap();
+
+    let ctx = context()?;
+    let channel_id = ChannelId(target.room_id.parse::<u64>()?);
+    let message_id = MessageId(target.id.parse::<u64>()?);
+
+    // Fails if the emoji isn't one discord knows about
+    let res = channel_id.create_reaction(ctx.http.clone(), message_id, emoji.clone()).await;
+    if let Err(serenity::Error::Http(err)) = &res {
+        // Discord answers unknown emoji with a 400
+        if err.status_code().map(|status| status.as_u16()) == Some(400) {
+            println!("Discord doesn't know the emoji {}", reaction.emoji);
+            return Ok(None);
+        }
+    }
+    res?;
+
+    return Ok(Some(Message {
+        service: "discord".to_owned(),
+        server_id: target.server_id,
+        room_id: target.room_id,
+        id: reaction_id(message_id, ctx.cache.current_user_id(), &emoji),
+    }));
+}
+
+pub async fn delete_reaction(store: &Store, reaction: Message) -> RelayResult<()> {
+    for relayed in store.reaction_relays(reaction.clone()).await? {
+        if relayed.service != "discord" {
+            continue;
+        }
+
+        // The bot's reaction stays until every matrix user has removed theirs
+        let others = store.reaction_origins(relayed.clone()).await?
+            .iter()
+            .filter(|origin| origin.id != reaction.id)
+            .count();
+        if others > 0 {
+            continue;
+        }
+
+        let parts = relayed.id.splitn(3, ":").collect::<Vec<&str>>();
+        if parts.len() != 3 {
+            return Err(RelayError::Invalid(format!("reaction id {}", relayed.id)));
+        }
+        let emoji = ReactionType::try_from(parts[2]);
+        if emoji.is_err() {
+            continue;
+        }
 
-    let relayed_messages = chat_service::message_relays(message.clone().message);
-    for msg in relayed_messages {
-        if msg.service == "discord" {
-            edit_message_webhook(webhook.clone(), msg.id, message.clone().content).await;
+        let http = context()?.http.clone();
+        let channel_id = ChannelId(relayed.room_id.parse::<u64>()?);
+        let message_id = MessageId(parts[0].parse::<u64>()?);
+        let res = channel_id.delete_reaction(http, message_id, None, emoji.unwrap()).await.map_err(RelayError::from);
+        if let Err(RelayError::NotFound(_)) = res {
+            continue;
         }
+        res?;
     }
+    return Ok(());
 }
@@ -0,0 +1,143 @@
+use std::{fmt, time::Duration};
+
+use ruma::api::client::error::ErrorKind;
+
+/// Why something couldn't be relayed
+#[derive(Debug, Clone)]
+pub enum RelayError {
+    /// The other service couldn't be reached, or gave a response we didn't expect
+    Transport(String),
+    /// The bot (or a puppet) isn't allowed to do this, e.g. a missing discord permission or matrix power level
+    Permission(String),
+    /// The room, channel, message or webhook doesn't exist (anymore)
+    NotFound(String),
+    /// The service asked us to slow down, with how long to wait if it said
+    RateLimited(Option<Duration>),
+    /// The database failed
+    Storage(String),
+    /// The event couldn't be understood, e.g. an id that doesn't parse
+    Invalid(String),
+}
+
+pub type RelayResult<T> = Result<T, RelayError>;
+
+impl RelayError {
+    /// Picks the error for an unsuccessful http status code
+    pub fn from_status(status: u16, context: String, retry_after: Option<Duration>) -> RelayError {
+        match status {
+            401 | 403 => RelayError::Permission(context),
+            404 => RelayError::NotFound(context),
+            429 => RelayError::RateLimited(retry_after),
+            _ => RelayError::Transport(format!("{} (status {})", context, status)),
+        }
+    }
+
+    /// Whether trying again later could work
+    pub fn is_temporary(&self) -> bool {
+        return matches!(self, RelayError::Transport(_) | RelayError::RateLimited(_) | RelayError::Storage(_));
+    }
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelayError::Transport(reason) => write!(f, "couldn't reach the server: {}", reason),
+            RelayError::Permission(reason) => write!(f, "missing permission: {}", reason),
+            RelayError::NotFound(what) => write!(f, "not found: {}", what),
+            RelayError::RateLimited(Some(retry_after)) => write!(f, "rate limited, retry after {:.1}s", retry_after.as_secs_f64()),
+            RelayError::RateLimited(None) => write!(f, "rate limited"),
+            RelayError::Storage(reason) => write!(f, "database error: {}", reason),
+            RelayError::Invalid(reason) => write!(f, "invalid event: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for RelayError {}
+
+impl From<rusqlite::Error> for RelayError {
+    fn from(err: rusqlite::Error) -> RelayError {
+        RelayError::Storage(err.to_string())
+    }
+}
+
+impl From<r2d2::Error> for RelayError {
+    fn from(err: r2d2::Error) -> RelayError {
+        RelayError::Storage(err.to_string())
+    }
+}
+
+impl From<postgres::Error> for RelayError {
+    fn from(err: postgres::Error) -> RelayError {
+        RelayError::Storage(err.to_string())
+    }
+}
+
+impl From<reqwest::Error> for RelayError {
+    fn from(err: reqwest::Error) -> RelayError {
+        if err.status().is_some() {
+            return RelayError::from_status(err.status().unwrap().as_u16(), err.to_string(), None);
+        }
+        RelayError::Transport(err.to_string())
+    }
+}
+
+impl From<serenity::Error> for RelayError {
+    fn from(err: serenity::Error) -> RelayError {
+        if let serenity::Error::Http(http_err) = &err {
+            if let Some(status) = http_err.status_code() {
+                return RelayError::from_status(status.as_u16(), err.to_string(), None);
+            }
+        }
+        if let serenity::Error::Model(_) = &err {
+            // Model errors are checks serenity does before sending, most of them are missing permissions
+            return RelayError::Permission(err.to_string());
+        }
+        RelayError::Transport(err.to_string())
+    }
+}
+
+// Matrix errors carry an errcode, which tells us more than the status
+fn from_matrix_kind(kind: Option<&ErrorKind>, context: String) -> RelayError {
+    match kind {
+        Some(ErrorKind::Forbidden) => RelayError::Permission(context),
+        Some(ErrorKind::NotFound) => RelayError::NotFound(context),
+        Some(ErrorKind::LimitExceeded { retry_after_ms }) => RelayError::RateLimited(*retry_after_ms),
+        _ => RelayError::Transport(context),
+    }
+}
+
+impl From<matrix_sdk::Error> for RelayError {
+    fn from(err: matrix_sdk::Error) -> RelayError {
+        from_matrix_kind(err.client_api_error_kind(), err.to_string())
+    }
+}
+
+impl From<matrix_sdk::HttpError> for RelayError {
+    fn from(err: matrix_sdk::HttpError) -> RelayError {
+        from_matrix_kind(err.client_api_error_kind(), err.to_string())
+    }
+}
+
+impl From<matrix_sdk_appservice::Error> for RelayError {
+    fn from(err: matrix_sdk_appservice::Error) -> RelayError {
+        RelayError::Transport(err.to_string())
+    }
+}
+
+impl From<ruma::IdParseError> for RelayError {
+    fn from(err: ruma::IdParseError) -> RelayError {
+        RelayError::Invalid(err.to_string())
+    }
+}
+
+impl From<std::num::ParseIntError> for RelayError {
+    fn from(err: std::num::ParseIntError) -> RelayError {
+        RelayError::Invalid(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for RelayError {
+    fn from(err: serde_json::Error) -> RelayError {
+        RelayError::Invalid(err.to_string())
+    }
+}
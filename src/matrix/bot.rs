@@ -11,13 +11,14 @@ use ruma::{
         room::{
             member::RoomMemberEventContent,
             message::{
-                MessageType, OriginalSyncRoomMessageEvent, Relation, RoomMessageEventContent,
-            }, redaction::OriginalSyncRoomRedactionEvent,
+                MessageFormat, MessageType, OriginalSyncRoomMessageEvent, Relation, RoomMessageEventContent,
+            }, redaction::OriginalSyncRoomRedactionEvent, MediaSource,
         },
+        reaction::OriginalSyncReactionEvent,
         AnyMessageLikeEventContent, AnyTimelineEvent, OriginalSyncMessageLikeEvent,
         StateEventContent,
     },
-    room_id, EventId, OwnedEventId, OwnedRoomId, RoomId, RoomOrAliasId,
+    room_id, EventId, MxcUri, OwnedEventId, OwnedRoomId, RoomId, RoomOrAliasId, UInt,
 };
 
 use matrix_sdk_appservice::{
@@ -38,10 +39,18 @@ use tracing::{info, trace};
 use tracing_subscriber::fmt::format::{self, Full};
 
 use crate::{
-    chat_service::{self, FullMessage, Message, User},
-    discord, CONFIG,
+    chat_service::{self, FullMessage, FullReaction, Message, Store, User},
+    cli, discord,
+    error::{RelayError, RelayResult},
+    outbox::{self, Delivery},
+    rooms,
+    config::config,
+    sequencer::{self, Ticket},
 };
 
+use super::commands;
+use super::format;
+
 pub static BOT_APPSERVICE: Mutex<Option<AppService>> = Mutex::new(None);
 pub static BOT_REGISTRATION: Mutex<Option<AppServiceRegistration>> = Mutex::new(None);
 pub static BOT_CLIENT: Mutex<Option<Client>> = Mutex::new(None);
@@ -57,14 +66,110 @@ fn find_ping(ping: String) -> String {
     let registration_local = (*(BOT_REGISTRATION.lock().unwrap())).clone().unwrap();
     let bot_localpart = registration_local.sender_localpart.clone().to_owned();
 
+    // Puppets are named after the discord user's id, anything else (like our own bot) is a normal matrix user
     if localpart.starts_with(bot_localpart.as_str()) {
-        return format!("<@{}>", &localpart[bot_localpart.len()..]);
-    } else {
-        return ping
-            .trim_start_matches("<")
-            .trim_end_matches(">")
-            .to_owned();
+        let discord_id = &localpart[bot_localpart.len()..];
+        if discord_id.len() > 0 && discord_id.chars().all(|c| c.is_ascii_digit()) {
+            return format!("<@{}>", discord_id);
+        }
+    }
+    return ping
+        .trim_start_matches("<")
+        .trim_end_matches(">")
+        .to_owned();
+}
+
+// Public download url for the media, so discord can link to it without a matrix account. Only with media_url in the
+// config, homeserver_url is often an address only the relay can reach
+pub fn mxc_to_url(mxc: &MxcUri) -> Option<String> {
+    let parts = mxc.parts();
+    let media_url = config().media_url.clone();
+    if parts.is_err() || media_url.is_none() {
+        return None;
+    }
+    let (server_name, media_id) = parts.unwrap();
+    return Some(format!(
+        "{}/_matrix/media/v3/download/{}/{}",
+        media_url.unwrap().trim_end_matches("/"),
+        server_name,
+        media_id
+    ));
+}
+
+// Public thumbnail url, used for avatars which only need to be small
+pub fn mxc_to_thumbnail_url(mxc: &MxcUri, size: u32) -> Option<String> {
+    let parts = mxc.parts();
+    let media_url = config().media_url.clone();
+    if parts.is_err() || media_url.is_none() {
+        return None;
+    }
+    let (server_name, media_id) = parts.unwrap();
+    return Some(format!(
+        "{}/_matrix/media/v3/thumbnail/{}/{}?width={}&height={}&method=crop",
+        media_url.unwrap().trim_end_matches("/"),
+        server_name,
+        media_id,
+        size,
+        size
+    ));
+}
+
+// Returns the attachment and the caption (if any) of a media event
+fn message_attachment(event: &OriginalSyncRoomMessageEvent) -> Option<(chat_service::Attachment, String)> {
+    let (body, source, content_type, size, width, height): (
+        String,
+        MediaSource,
+        Option<String>,
+        Option<UInt>,
+        Option<UInt>,
+        Option<UInt>,
+    ) = match &event.content.msgtype {
+        MessageType::Image(c) => {
+            let info = c.info.clone().unwrap_or_default();
+            (c.body.clone(), c.source.clone(), info.mimetype, info.size, info.width, info.height)
+        }
+        MessageType::Video(c) => {
+            let info = c.info.clone().unwrap_or_default();
+            (c.body.clone(), c.source.clone(), info.mimetype, info.size, info.width, info.height)
+        }
+        MessageType::Audio(c) => {
+            let info = c.info.clone().unwrap_or_default();
+            (c.body.clone(), c.source.clone(), info.mimetype, info.size, None, None)
+        }
+        MessageType::File(c) => {
+            let info = c.info.clone().unwrap_or_default();
+            (c.body.clone(), c.source.clone(), info.mimetype, info.size, None, None)
+        }
+        _ => return None,
+    };
+
+    // Encrypted media can't be relayed, plain media is downloaded by the relay itself
+    let url = match source {
+        MediaSource::Plain(mxc) => Some(mxc.to_string()),
+        MediaSource::Encrypted(_) => None,
+    };
+    if url.is_none() {
+        return None;
     }
+
+    // Newer clients send the file name seperately, in which case body is a caption
+    let content_json = serde_json::to_value(&event.content).unwrap_or_default();
+    let filename = content_json["filename"].as_str().unwrap_or(body.as_str()).to_owned();
+    let mut caption = "".to_owned();
+    if filename != body {
+        caption = body;
+    }
+
+    let attachment = chat_service::Attachment {
+        filename: filename,
+        url: url.unwrap(),
+        content_type: content_type,
+        size: size.map(|size| u64::from(size)).unwrap_or(0),
+        width: width.map(|width| u64::from(width)),
+        height: height.map(|height| u64::from(height)),
+        thumbnail_url: None,
+    };
+    return Some((attachment, caption));
 }
 
 fn strip_reply(msg: String) -> String {
@@ -80,34 +185,36 @@ fn strip_reply(msg: String) -> String {
 }
 
 async fn format_for_reply_event_id(
+    store: &Store,
     message: FullMessage,
     reply_id: OwnedEventId,
     content: String,
     room: Joined,
-) -> FullMessage {
+) -> RelayResult<FullMessage> {
     let mut relay_msg = message.clone();
 
     let mut reply_header = "".to_owned();
 ;
     let reply_data = room
         .event(&reply_id)
-        .await
-        .unwrap()
+        .await?
         .event
         .json()
         .to_string();
-    let v: serde_json::Value = serde_json::from_str(&reply_data).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&reply_data)?;
 
-    let mut reply_body = v["content"]["body"].as_str().unwrap().to_owned();
+    let mut reply_body = v["content"]["body"].as_str().unwrap_or("").to_owned();
     reply_body = strip_reply(reply_body);
 
-    let reply_author = v["sender"].as_str().unwrap().to_owned();
+    let reply_author = v["sender"].as_str().unwrap_or("").to_owned();
     let author_ping = find_ping(reply_author);
 
-    let mut header = reply_body.lines().collect::<Vec<&str>>()[0].to_owned();
-    if header.len() > 64 {
-        header = format!("{}...", &header[..64]);
+    let mut header = reply_body.lines().next().unwrap_or("").to_owned();
+    // Cut by characters, slicing the string could land inside one
+    if header.chars().count() > 64 {
+        header = format!("{}...", header.chars().take(64).collect::<String>());
     }
+    header = format::escape_discord(&header);
 
     let reply_msg = Message {
         service: "matrix".to_owned(),
@@ -116,7 +223,7 @@ async fn format_for_reply_event_id(
         id: reply_id.to_string(),
     };
 
-    let relayed_messages = chat_service::message_relays(reply_msg.clone());
+    let relayed_messages = store.message_relays(reply_msg.clone()).await?;
     let mut discord_msg_url = "".to_owned();
     for msg in relayed_messages {
         if msg.service == "discord" {
@@ -126,7 +233,7 @@ async fn format_for_reply_event_id(
             );
         }
     }
-    let origin_message = chat_service::message_origin(reply_msg.clone());
+    let origin_message = store.message_origin(reply_msg.clone()).await?;
     if origin_message.is_some() {
         if origin_message.clone().unwrap().service == "discord" {
             discord_msg_url = format!(
@@ -147,29 +254,179 @@ async fn format_for_reply_event_id(
     relay_msg.content = format!(
         "{}\n{}",
         reply_header,
-        strip_reply(content)
+        content
     );
-    return relay_msg;
+    return Ok(relay_msg);
 }
 
 async fn format_for_reply(
+    store: &Store,
     message: FullMessage,
     event: OriginalSyncRoomMessageEvent,
     room: Joined,
-) -> FullMessage {
+) -> RelayResult<FullMessage> {
     if event.content.relates_to.is_some() {
         match event.content.clone().relates_to.unwrap() {
             Relation::Reply { in_reply_to } => {
                 let reply_id = in_reply_to.event_id;
-                return format_for_reply_event_id(message, reply_id, event.content.body().to_owned(), room).await;
+                let content = message.content.clone();
+                return format_for_reply_event_id(store, message, reply_id, content, room).await;
+            }
+            // Thread messages only count as replies when the client says it isn't just a fallback
+            Relation::Thread(thread) => {
+                if !thread.is_falling_back && thread.in_reply_to.is_some() {
+                    let reply_id = thread.in_reply_to.unwrap().event_id;
+                    let content = message.content.clone();
+                    return format_for_reply_event_id(store, message, reply_id, content, room).await;
+                }
             }
             _ => {}
         }
     }
-    return message;
+    return Ok(message);
+}
+
+// Puppets are turned back into a ping of the discord user they belong to
+fn pill_to_discord(user_id: &str, text: &str) -> String {
+    let ping = find_ping(user_id.to_owned());
+    if ping.starts_with("<@") {
+        return ping;
+    }
+    return format!("**{}**", format::escape_discord(text));
+}
+
+// Uses the html body when there is one, otherwise the plain body is escaped so it shows up as sent
+fn message_type_to_discord(msgtype: &MessageType, is_reply: bool) -> String {
+    let (body, formatted) = match msgtype {
+        MessageType::Text(c) => (c.body.clone(), c.formatted.clone()),
+        MessageType::Notice(c) => (c.body.clone(), c.formatted.clone()),
+        MessageType::Emote(c) => (c.body.clone(), c.formatted.clone()),
+        _ => (msgtype.body().to_owned(), None),
+    };
+
+    if formatted.is_some() && matches!(formatted.as_ref().unwrap().format, MessageFormat::Html) {
+        return format::html_to_discord(&formatted.unwrap().body, &pill_to_discord);
+    }
+
+    // Plain replies start with a quote of the message being replied to
+    let mut body = body;
+    if is_reply {
+        body = strip_reply(body);
+    }
+    return format::escape_discord(body.trim_end());
+}
+
+fn sender_to_user(sender: &UserId) -> User {
+    return User {
+        source: "matrix".to_owned(),
+        id: sender.to_string(),
+        ping: format!("<@{}>", sender.to_string()),
+        tag: sender.to_string(),
+        display: sender.to_string(),
+        name: sender.localpart().to_owned(),
+        avatar: None,
+    };
+}
+
+// Uses the display name and avatar the sender has in this room, falling back to their mxid
+async fn sender_to_room_user(sender: &UserId, room: &Joined) -> User {
+    let mut user = sender_to_user(sender);
+    let member = room.get_member(sender).await;
+    if member.is_err() || member.as_ref().unwrap().is_none() {
+        return user;
+    }
+    let member = member.unwrap().unwrap();
+
+    if member.display_name().is_some() {
+        user.display = member.display_name().unwrap().to_owned();
+    }
+    user.avatar = member.avatar_url().and_then(|mxc| mxc_to_thumbnail_url(mxc, 128));
+    return user;
+}
+
+// Replies to the sender's message so they know it didn't make it to discord
+pub async fn notify_failure(source: &Message, err: &RelayError) {
+    if !config().failure_notices.unwrap_or(true) {
+        return;
+    }
+
+    let res = send_failure_notice(source, err).await;
+    if res.is_err() {
+        println!("Failed to send failure notice for {}: {}", source.id, res.err().unwrap());
+    }
+}
+
+async fn send_failure_notice(source: &Message, err: &RelayError) -> RelayResult<()> {
+    let client = (*(BOT_CLIENT.lock().expect("Bot client is poisoned"))).clone();
+    let room_id = RoomId::parse(source.room_id.as_str())?;
+    let room = client
+        .and_then(|client| client.get_joined_room(&room_id))
+        .ok_or(RelayError::NotFound(format!("the bot isn't in room {}", room_id)))?;
+
+    let mut content = RoomMessageEventContent::notice_plain(format!("This message couldn't be delivered to Discord ({})", err));
+    content.relates_to = Some(Relation::Reply { in_reply_to: InReplyTo::new(EventId::parse(source.id.as_str())?) });
+    room.send(content, None).await?;
+    return Ok(());
+}
+
+/// The discord content of an edit, from the original event and the new content. None when someone else than the
+/// sender edited it
+pub fn edited_content(original: &serde_json::Value, sender: &str, new_content: &MessageType) -> Option<String> {
+    if original["sender"].as_str() != Some(sender) {
+        return None;
+    }
+
+    return Some(match new_content {
+        MessageType::Text(_) | MessageType::Notice(_) | MessageType::Emote(_) => message_type_to_discord(new_content, false),
+        // The file stays as it is on discord, only the caption can change. Without a caption the body is the file name
+        _ => {
+            let body = new_content.body();
+            let filename = original["content"]["filename"].as_str().or(original["content"]["body"].as_str()).unwrap_or("");
+            if body != filename { format::escape_discord(body) } else { "".to_owned() }
+        }
+    });
+}
+
+// Edits keep the reply header of the message they replace. Like clients do, an edit only counts if it comes from the
+// sender of the original message
+async fn edit_message(
+    store: &Store,
+    mut relay_msg: FullMessage,
+    sender: &UserId,
+    event_id: OwnedEventId,
+    new_content: &MessageType,
+    room: Joined,
+    ticket: &Ticket,
+) -> RelayResult<()> {
+    // The original might still be in the outbox, so whether it made it to discord is only checked on delivery
+    let edit_data = room
+        .event(&event_id)
+        .await?
+        .event
+        .json()
+        .to_string();
+    let v: serde_json::Value = serde_json::from_str(&edit_data)?;
+
+    let content = edited_content(&v, sender.as_str(), new_content);
+    if content.is_none() {
+        println!("Ignoring edit of {} by {}, who didn't send it", event_id, sender);
+        return Ok(());
+    }
+
+    relay_msg.attachments = Vec::new();
+    relay_msg.content = content.unwrap();
+
+    let reply_event = v["content"]["m.relates_to"]["m.in_reply_to"]["event_id"].as_str();
+    if reply_event.is_some() {
+        let reply_event = EventId::parse(reply_event.unwrap())?;
+        relay_msg = format_for_reply_event_id(store, relay_msg.clone(), reply_event, relay_msg.clone().content, room).await?;
+    }
+    ticket.turn().await;
+    return outbox::enqueue(store, Delivery::DiscordEdit { message: relay_msg }).await;
 }
 
-async fn handle_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
+async fn handle_room_message(event: OriginalSyncRoomMessageEvent, room: Room, Ctx(store): Ctx<Store>) {
+    let ticket = sequencer::ticket("matrix", room.room_id().as_str());
     println!("GOT MESSAGE");
     println!("{}", event.content.body());
 
@@ -181,8 +438,14 @@ async fn handle_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
     }
 
     if let Room::Joined(room) = room {
-        let m = CONFIG.room.iter().find(|m| m.matrix == room.room_id().to_string());
-        if m.is_none() {
+        // Commands work in rooms that aren't bridged (yet) too, and aren't relayed
+        let command = commands::parse_command(event.content.body());
+        if command.is_some() {
+            commands::run_command(&store, &room, &event.sender, command.unwrap()).await;
+            return;
+        }
+
+        if rooms::by_matrix(room.room_id().as_str()).is_none() {
             return;
         }
 
@@ -193,48 +456,48 @@ async fn handle_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
             id: event.event_id.to_string(),
         };
 
-        let user = User {
-            source: "matrix".to_owned(),
-            id: event.sender.to_string(),
-            ping: format!("<@{}>", event.sender.to_string()),
-            tag: event.sender.to_string(),
-            display: event.sender.to_string(),
-            avatar: None,
-        };
+        let user = sender_to_room_user(&event.sender, &room).await;
 
+        let is_reply = matches!(event.content.relates_to, Some(Relation::Reply { .. }));
         let mut relay_msg = FullMessage {
             message: msg,
             user: user,
-            content: event.content.body().to_string(),
+            content: message_type_to_discord(&event.content.msgtype, is_reply),
             reply: None,
+            attachments: Vec::new(),
+            mentions: Vec::new(),
+            thread: None,
         };
+
+        if let Some(Relation::Thread(thread)) = &event.content.relates_to {
+            relay_msg.thread = Some(Message {
+                service: "matrix".to_owned(),
+                server_id: "".to_owned(),
+                room_id: room.room_id().to_string(),
+                id: thread.event_id.to_string(),
+            });
+        }
+
+        let attachment = message_attachment(&event);
+        if attachment.is_some() {
+            let (attachment, caption) = attachment.unwrap();
+            relay_msg.content = format::escape_discord(&caption);
+            relay_msg.attachments.push(attachment);
+        }
         //let content = RoomMessageEventContent::text_plain("🎉🎊🥳 let's PARTY!! 🥳🎊🎉");
 
         if event.content.relates_to.is_some() {
             match event.content.clone().relates_to.unwrap() {
                 Relation::Replacement(r) => {
                     let event_id = r.event_id;
-                    let edit_data = room
-                        .event(&event_id)
-                        .await
-                        .unwrap()
-                        .event
-                        .json()
-                        .to_string();
-                    let v: serde_json::Value = serde_json::from_str(&edit_data).unwrap();
-
                     relay_msg.message.id = event_id.to_string();
-                    if v["content"]["m.relates_to"].get("m.in_reply_to").is_some() {
-                        println!("Is reply!");
-                        let reply_event = v["content"]["m.relates_to"]["m.in_reply_to"]["event_id"].as_str().unwrap();
-                        let reply_event = EventId::parse(reply_event).unwrap();
-                        relay_msg = format_for_reply_event_id(relay_msg.clone(), reply_event, relay_msg.clone().content, room).await;
-                    }
-                    else
-                    {
-                        println!("Isn't reply!");
+
+                    let res = edit_message(&store, relay_msg.clone(), &event.sender, event_id, &r.new_content, room.clone(), &ticket).await;
+                    if res.is_err() {
+                        let err = res.err().unwrap();
+                        println!("Failed to relay edit {} in {}: {}", event.event_id, room.room_id(), err);
+                        notify_failure(&relay_msg.message, &err).await;
                     }
-                    discord::relay::edit_message(relay_msg).await;
                     return;
                 }
                 _ => {}
@@ -243,9 +506,15 @@ async fn handle_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
 
         println!("sending");
 
-        relay_msg = format_for_reply(relay_msg.clone(), event, room).await;
-        let discord_msg = discord::relay::relay_message(relay_msg.clone()).await;
-        chat_service::create_message(relay_msg.message, discord_msg);
+        let event_id = event.event_id.clone();
+        let formatted = format_for_reply(&store, relay_msg.clone(), event, room.clone()).await;
+        if formatted.is_err() {
+            println!("Failed to quote the reply of {}: {}", event_id, formatted.as_ref().err().unwrap());
+        }
+        relay_msg = formatted.unwrap_or(relay_msg);
+
+        ticket.turn().await;
+        outbox::enqueue_or_log(&store, Delivery::DiscordMessage { message: relay_msg }).await;
         // send our message to the room we found the "!party" command in
         // the last parameter is an optional transaction id which we don't
         // care about.
@@ -266,8 +535,9 @@ async fn handle_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
     }
 }
 
-async fn handle_message_redact(event: OriginalSyncRoomRedactionEvent, room: Room)
+async fn handle_message_redact(event: OriginalSyncRoomRedactionEvent, room: Room, Ctx(store): Ctx<Store>)
 {
+    let ticket = sequencer::ticket("matrix", room.room_id().as_str());
     if let Room::Joined(room) = room {
         let msg = chat_service::Message {
             service: "matrix".to_owned(),
@@ -275,13 +545,53 @@ async fn handle_message_redact(event: OriginalSyncRoomRedactionEvent, room: Room
             room_id: room.room_id().to_string(),
             id: event.redacts.to_string(),
         };
-        
-        discord::relay::delete_message(msg.clone()).await;
-        chat_service::delete_message(msg.clone());
+
+        ticket.turn().await;
+        outbox::enqueue_or_log(&store, Delivery::DiscordDelete { message: msg.clone() }).await;
+
+        // The redacted event may have been a reaction instead
+        outbox::enqueue_or_log(&store, Delivery::DiscordDeleteReaction { reaction: msg }).await;
+    }
+}
+
+async fn handle_reaction(event: OriginalSyncReactionEvent, room: Room, Ctx(store): Ctx<Store>)
+{
+    let ticket = sequencer::ticket("matrix", room.room_id().as_str());
+    let registration_local = (*(BOT_REGISTRATION.lock().unwrap())).clone().unwrap();
+    let bot_localpart = registration_local.sender_localpart.clone();
+    if event.sender.localpart().starts_with(&bot_localpart) {
+        return;
+    }
+
+    if let Room::Joined(room) = room {
+        if rooms::by_matrix(room.room_id().as_str()).is_none() {
+            return;
+        }
+
+        let annotation = event.content.relates_to.clone();
+        let reaction = FullReaction {
+            user: sender_to_user(&event.sender),
+            reaction: Message {
+                service: "matrix".to_owned(),
+                server_id: "".to_owned(),
+                room_id: room.room_id().to_string(),
+                id: event.event_id.to_string(),
+            },
+            message: Message {
+                service: "matrix".to_owned(),
+                server_id: "".to_owned(),
+                room_id: room.room_id().to_string(),
+                id: annotation.event_id.to_string(),
+            },
+            emoji: annotation.key,
+        };
+
+        ticket.turn().await;
+        outbox::enqueue_or_log(&store, Delivery::DiscordReaction { reaction: reaction }).await;
     }
 }
 
-pub async fn start_bot() -> Result<()> {
+pub async fn start_bot(store: Store) -> Result<()> {
     // Currently this causes a stack overflow on windows, stack size has been increased during compilation as a temporary fix.
     // TODO: Find better fix
 
@@ -290,11 +600,11 @@ pub async fn start_bot() -> Result<()> {
 
     println!("Starting!");
 
-    let homeserver_url: String = CONFIG.homeserver_url.clone();
-    let server_name: String = CONFIG.server_name.clone();
+    let homeserver_url: String = config().homeserver_url.clone();
+    let server_name: String = config().server_name.clone();
 
     let registration_local = Some(AppServiceRegistration::try_from_yaml_file(
-        "./appservice-registration.yaml",
+        cli::paths().registration,
     )?);
 
     println!("Loaded config!");
@@ -352,10 +662,14 @@ pub async fn start_bot() -> Result<()> {
         println!("Failed to set display name");
     }
 
-    for mroom in CONFIG.room.iter() {
+    for mroom in rooms::all().iter() {
         let roomid = mroom.matrix.clone();
         let id: Box<RoomId> = RoomId::parse_box(roomid.as_ref()).unwrap();
-        user.join_room_by_id(id.as_ref()).await?;
+        // One room the bot can't get into shouldn't stop the others from being bridged
+        let joined = user.join_room_by_id(id.as_ref()).await;
+        if joined.is_err() {
+            println!("Failed to join {}: {}", roomid, joined.err().unwrap());
+        }
     }
 
     println!("Joined rooms");
@@ -379,15 +693,18 @@ pub async fn start_bot() -> Result<()> {
     println!("Registering events");
 
     user.add_event_handler_context(appservice_local.clone());
+    user.add_event_handler_context(store);
     user.add_event_handler(handle_room_message);
     user.add_event_handler(handle_message_redact);
+    user.add_event_handler(handle_reaction);
 
     print!("Splitting");
 
     // Appservice should be accessible by the server!
     //let (host, port) = appservice_local.as_ref().unwrap().registration().get_host_and_port()?;
     // Appservice may not be hosted on same server as matrix server, so we allow it to be set seperately
-    let host: Vec<&str> = CONFIG.host.split(":").collect();
+    let current = config();
+    let host: Vec<&str> = current.host.split(":").collect();
 
     println!("Starting!");
 
@@ -0,0 +1,51 @@
+// Bridge commands sent in a matrix room, only the admins in the config can use them
+
+use matrix_sdk::room::Joined;
+use ruma::{events::room::message::RoomMessageEventContent, UserId};
+
+use crate::{chat_service::Store, config::config, rooms};
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    // Bridges the room the command was sent in with a discord channel
+    Bridge(String),
+    Unbridge,
+    // A command without what it needs
+    Usage(&'static str),
+}
+
+pub fn parse_command(body: &str) -> Option<Command> {
+    let mut words = body.split_whitespace();
+    match words.next() {
+        Some("!bridge") => {
+            let channel = words.next();
+            if channel.is_none() || channel.unwrap().parse::<u64>().is_err() {
+                return Some(Command::Usage("!bridge <discord channel id>"));
+            }
+            return Some(Command::Bridge(channel.unwrap().to_owned()));
+        }
+        Some("!unbridge") => return Some(Command::Unbridge),
+        _ => return None,
+    }
+}
+
+fn is_admin(user: &UserId) -> bool {
+    return config().admins.as_ref().map(|admins| admins.iter().any(|admin| admin == user.as_str())).unwrap_or(false);
+}
+
+pub async fn run_command(store: &Store, room: &Joined, sender: &UserId, command: Command) {
+    let reply = if !is_admin(sender) {
+        "Only bridge admins can do that".to_owned()
+    } else {
+        match command {
+            Command::Bridge(channel) => rooms::bridge_command(store, &channel, room.room_id().as_str()).await,
+            Command::Unbridge => rooms::unbridge_command(store, room.room_id().as_str()).await,
+            Command::Usage(usage) => format!("Usage: {}", usage),
+        }
+    };
+
+    let res = room.send(RoomMessageEventContent::notice_plain(reply), None).await;
+    if res.is_err() {
+        println!("Failed to answer a command in {}: {}", room.room_id(), res.err().unwrap());
+    }
+}
@@ -0,0 +1,418 @@
+// Converts matrix's org.matrix.custom.html into discord flavoured markdown.
+// Matrix only allows a small set of tags, so rather than pulling in a full html parser we
+// tokenize it ourselves and build a small tree to render from.
+
+#[derive(Clone, Debug)]
+enum Node {
+    Element {
+        name: String,
+        attrs: Vec<(String, String)>,
+        children: Vec<Node>,
+    },
+    Text(String),
+}
+
+// Tags which never have children
+const VOID_TAGS: [&str; 3] = ["br", "hr", "img"];
+
+fn decode_entities(text: &str) -> String {
+    let mut out = "".to_owned();
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = rest.find(';');
+        if end.is_none() || end.unwrap() > 10 {
+            out.push('&');
+            rest = &rest[1..];
+            continue;
+        }
+        let end = end.unwrap();
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+                u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32)
+            }
+            _ if entity.starts_with('#') => entity[1..].parse::<u32>().ok().and_then(char::from_u32),
+            _ => None,
+        };
+
+        if decoded.is_some() {
+            out.push(decoded.unwrap());
+            rest = &rest[end + 1..];
+        } else {
+            out.push('&');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    return out;
+}
+
+fn parse_attrs(tag: &str) -> Vec<(String, String)> {
+    let mut attrs = Vec::new();
+    let chars = tag.chars().collect::<Vec<char>>();
+    let mut i = 0;
+
+    while i < chars.len() {
+        while i < chars.len() && (chars[i].is_whitespace() || chars[i] == '/') {
+            i += 1;
+        }
+
+        let mut name = "".to_owned();
+        while i < chars.len() && !chars[i].is_whitespace() && chars[i] != '=' && chars[i] != '/' {
+            name.push(chars[i]);
+            i += 1;
+        }
+        if name == "" {
+            break;
+        }
+
+        while i < chars.len() && chars[i].is_whitespace() {
+            i += 1;
+        }
+
+        let mut value = "".to_owned();
+        if i < chars.len() && chars[i] == '=' {
+            i += 1;
+            while i < chars.len() && chars[i].is_whitespace() {
+                i += 1;
+            }
+            if i < chars.len() && (chars[i] == '"' || chars[i] == '\'') {
+                let quote = chars[i];
+                i += 1;
+                while i < chars.len() && chars[i] != quote {
+                    value.push(chars[i]);
+                    i += 1;
+                }
+                i += 1;
+            } else {
+                while i < chars.len() && !chars[i].is_whitespace() {
+                    value.push(chars[i]);
+                    i += 1;
+                }
+            }
+        }
+
+        attrs.push((name.to_lowercase(), decode_entities(&value)));
+    }
+    return attrs;
+}
+
+fn parse(html: &str) -> Vec<Node> {
+    // Stack of open elements, the bottom one is a fake root
+    let mut stack: Vec<(String, Vec<(String, String)>, Vec<Node>)> = vec![("".to_owned(), Vec::new(), Vec::new())];
+    let mut rest = html;
+
+    while rest.len() > 0 {
+        let lt = rest.find('<');
+        if lt.is_none() {
+            stack.last_mut().unwrap().2.push(Node::Text(decode_entities(rest)));
+            break;
+        }
+        let lt = lt.unwrap();
+        if lt > 0 {
+            stack.last_mut().unwrap().2.push(Node::Text(decode_entities(&rest[..lt])));
+        }
+        rest = &rest[lt..];
+
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").map(|end| end + 3).unwrap_or(rest.len());
+            rest = &rest[end..];
+            continue;
+        }
+
+        let gt = rest.find('>');
+        if gt.is_none() {
+            // Not actually a tag
+            stack.last_mut().unwrap().2.push(Node::Text(decode_entities(rest)));
+            break;
+        }
+        let gt = gt.unwrap();
+        let tag = &rest[1..gt];
+        rest = &rest[gt + 1..];
+
+        if tag.starts_with('/') {
+            let name = tag[1..].trim().to_lowercase();
+            // Close everything up to the matching tag, ignore stray closing tags
+            if stack.iter().skip(1).any(|open| open.0 == name) {
+                loop {
+                    let (open_name, attrs, children) = stack.pop().unwrap();
+                    let done = open_name == name;
+                    stack.last_mut().unwrap().2.push(Node::Element { name: open_name, attrs, children });
+                    if done {
+                        break;
+                    }
+                }
+            }
+            continue;
+        }
+
+        let name_end = tag.find(|c: char| c.is_whitespace() || c == '/').unwrap_or(tag.len());
+        let name = tag[..name_end].to_lowercase();
+        if name == "" || name.starts_with('!') {
+            continue;
+        }
+        let attrs = parse_attrs(&tag[name_end..]);
+
+        if VOID_TAGS.contains(&name.as_str()) || tag.ends_with('/') {
+            stack.last_mut().unwrap().2.push(Node::Element { name, attrs, children: Vec::new() });
+        } else {
+            stack.push((name, attrs, Vec::new()));
+        }
+    }
+
+    // Close anything left open
+    while stack.len() > 1 {
+        let (name, attrs, children) = stack.pop().unwrap();
+        stack.last_mut().unwrap().2.push(Node::Element { name, attrs, children });
+    }
+    return stack.pop().unwrap().2;
+}
+
+fn attr<'a>(attrs: &'a Vec<(String, String)>, name: &str) -> Option<&'a str> {
+    return attrs.iter().find(|attr| attr.0 == name).map(|attr| attr.1.as_str());
+}
+
+/// Escapes characters discord would treat as formatting. Links are left alone so they still work.
+pub fn escape_discord(text: &str) -> String {
+    let mut out = "".to_owned();
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+        out.push_str(indent);
+        // These only do something at the start of a line
+        if trimmed.starts_with('>') || trimmed.starts_with('#') || trimmed.starts_with("- ") {
+            out.push('\\');
+        }
+
+        let mut first = true;
+        for word in trimmed.split(' ') {
+            if !first {
+                out.push(' ');
+            }
+            first = false;
+
+            if word.starts_with("http://") || word.starts_with("https://") {
+                out.push_str(word);
+                continue;
+            }
+            for c in word.chars() {
+                if "\\*_~`|".contains(c) {
+                    out.push('\\');
+                }
+                out.push(c);
+            }
+        }
+    }
+    return out;
+}
+
+struct Renderer<'a> {
+    pill: &'a dyn Fn(&str, &str) -> String,
+    lists: Vec<(bool, usize)>, // (ordered, next number) for each list we are inside of
+}
+
+impl<'a> Renderer<'a> {
+    fn text(&self, nodes: &Vec<Node>) -> String {
+        let mut out = "".to_owned();
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Element { name, children, .. } => {
+                    if name == "br" {
+                        out.push('\n');
+                    } else {
+                        out.push_str(&self.text(children));
+                    }
+                }
+            }
+        }
+        return out;
+    }
+
+    fn render_children(&mut self, nodes: &Vec<Node>) -> String {
+        let mut out = "".to_owned();
+        for node in nodes {
+            out.push_str(&self.render(node));
+        }
+        return out;
+    }
+
+    // Wraps inline formatting around the content, keeping surrounding whitespace outside of the markers
+    fn wrap(content: String, marker: &str) -> String {
+        let trimmed = content.trim();
+        if trimmed == "" {
+            return content;
+        }
+        let start = &content[..content.len() - content.trim_start().len()];
+        let end = &content[content.trim_end().len()..];
+        return format!("{}{}{}{}{}", start, marker, trimmed, marker, end);
+    }
+
+    fn block(content: String) -> String {
+        return format!("\n{}\n", content.trim_matches('\n'));
+    }
+
+    fn render(&mut self, node: &Node) -> String {
+        let (name, attrs, children) = match node {
+            Node::Text(text) => {
+                // Html whitespace, newlines don't mean anything here
+                let collapsed = text.replace(['\n', '\r', '\t'], " ");
+                return escape_discord(&collapsed);
+            }
+            Node::Element { name, attrs, children } => (name.as_str(), attrs, children),
+        };
+
+        match name {
+            // Reply fallback, the relay adds its own reply header
+            "mx-reply" => "".to_owned(),
+            "br" => "\n".to_owned(),
+            "hr" => "\n───\n".to_owned(),
+            "strong" | "b" => Self::wrap(self.render_children(children), "**"),
+            "em" | "i" => Self::wrap(self.render_children(children), "*"),
+            "u" => Self::wrap(self.render_children(children), "__"),
+            "del" | "s" | "strike" => Self::wrap(self.render_children(children), "~~"),
+            "code" => {
+                let code = self.text(children);
+                if code.contains('`') {
+                    format!("`` {} ``", code)
+                } else {
+                    format!("`{}`", code)
+                }
+            }
+            "pre" => {
+                let mut language = "";
+                for child in children {
+                    if let Node::Element { name, attrs, .. } = child {
+                        if name == "code" {
+                            language = attr(attrs, "class")
+                                .and_then(|class| class.split(' ').find(|c| c.starts_with("language-")))
+                                .map(|class| &class["language-".len()..])
+                                .unwrap_or("");
+                        }
+                    }
+                }
+                let code = self.text(children);
+                Self::block(format!("```{}\n{}\n```", language, code.trim_end_matches('\n')))
+            }
+            "a" => {
+                let href = attr(attrs, "href").unwrap_or("");
+                let text = self.render_children(children);
+
+                let matrix_to = "https://matrix.to/#/";
+                if href.starts_with(matrix_to) && href[matrix_to.len()..].starts_with('@') {
+                    let user_id = href[matrix_to.len()..].split(|c| c == '?' || c == '/').next().unwrap();
+                    let user_id = user_id.replace("%40", "@").replace("%3A", ":").replace("%3a", ":");
+                    return (self.pill)(&user_id, &self.text(children));
+                }
+
+                if href == "" || self.text(children) == href {
+                    text
+                } else {
+                    // Angle brackets stop discord from embedding a preview of every link
+                    format!("[{}](<{}>)", text, href)
+                }
+            }
+            "span" | "font" => {
+                let content = self.render_children(children);
+                if attr(attrs, "data-mx-spoiler").is_some() {
+                    Self::wrap(content, "||")
+                } else {
+                    content
+                }
+            }
+            "img" => {
+                let alt = attr(attrs, "alt").or(attr(attrs, "title")).unwrap_or("");
+                escape_discord(alt)
+            }
+            "h1" | "h2" | "h3" => {
+                let level = name[1..].parse::<usize>().unwrap();
+                Self::block(format!("{} {}", "#".repeat(level), self.render_children(children).trim()))
+            }
+            // Discord only has three heading levels
+            "h4" | "h5" | "h6" => Self::block(Self::wrap(self.render_children(children), "**")),
+            "blockquote" => {
+                let content = self.render_children(children);
+                let quoted = content
+                    .trim_matches('\n')
+                    .lines()
+                    .map(|line| format!("> {}", line))
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                Self::block(quoted)
+            }
+            "ul" | "ol" => {
+                let start = attr(attrs, "start").and_then(|start| start.parse::<usize>().ok()).unwrap_or(1);
+                self.lists.push((name == "ol", start));
+                let content = self.render_children(children);
+                self.lists.pop();
+                Self::block(content)
+            }
+            "li" => {
+                let depth = self.lists.len().max(1) - 1;
+                let marker = match self.lists.last_mut() {
+                    Some((true, number)) => {
+                        *number += 1;
+                        format!("{}.", *number - 1)
+                    }
+                    _ => "-".to_owned(),
+                };
+                let indent = "  ".repeat(depth);
+                let content = self.render_children(children);
+                let mut lines = content.trim_matches('\n').lines();
+                let mut out = format!("{}{} {}", indent, marker, lines.next().unwrap_or("").trim_start());
+                let nested_indent = format!("{}  ", indent);
+                for line in lines {
+                    // Nested lists are already indented
+                    if line.starts_with(&nested_indent) {
+                        out.push_str(&format!("\n{}", line));
+                    } else {
+                        out.push_str(&format!("\n{}{}", nested_indent, line));
+                    }
+                }
+                format!("\n{}", out)
+            }
+            "p" | "div" | "table" => format!("{}\n", Self::block(self.render_children(children))),
+            "tr" => format!("{}\n", self.render_children(children).trim()),
+            "td" | "th" => format!("{} ", self.render_children(children).trim()),
+            _ => self.render_children(children),
+        }
+    }
+}
+
+/// Converts matrix html into discord markdown.
+/// `pill` is called with the user id and text of each mention pill, and returns what to put in its place.
+pub fn html_to_discord(html: &str, pill: &dyn Fn(&str, &str) -> String) -> String {
+    let nodes = parse(html);
+    let mut renderer = Renderer { pill: pill, lists: Vec::new() };
+    let rendered = renderer.render_children(&nodes);
+
+    // Block elements add newlines freely, so tidy them up
+    let lines = rendered.lines().map(|line| line.trim_end()).collect::<Vec<&str>>();
+    let mut out = "".to_owned();
+    let mut newlines = 0;
+    for c in lines.join("\n").trim().chars() {
+        if c == '\n' {
+            newlines += 1;
+            if newlines > 2 {
+                continue;
+            }
+        } else {
+            newlines = 0;
+        }
+        out.push(c);
+    }
+    return out;
+}
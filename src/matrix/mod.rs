@@ -1,2 +1,5 @@
 pub mod bot;
+pub mod commands;
+pub mod format;
+pub mod registration;
 pub mod relay;
\ No newline at end of file
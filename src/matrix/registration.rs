@@ -0,0 +1,143 @@
+// The appservice registration the homeserver is given. It has to agree with the config on who the puppets are and
+// where the relay listens, so it is generated from the config and checked against it on startup.
+
+use anyhow::{anyhow, Context};
+use matrix_sdk_appservice::AppServiceRegistration;
+use rand::{distributions::Alphanumeric, Rng};
+use regex::Regex;
+use ruma::api::appservice::Registration;
+
+use crate::Outer;
+
+pub const DEFAULT_PUPPET_PREFIX: &str = "_appservice_";
+const TOKEN_LENGTH: usize = 64;
+
+/// The start of the localpart of every puppet, which is also the registration's sender_localpart
+pub fn puppet_prefix(config: &Outer) -> String {
+    return config.puppet_prefix.clone().unwrap_or(DEFAULT_PUPPET_PREFIX.to_owned());
+}
+
+/// The users namespace of the puppets, e.g. @_appservice_.*:example\.com
+pub fn users_regex(prefix: &str, server_name: &str) -> String {
+    return format!("@{}.*:{}", regex::escape(prefix), regex::escape(server_name));
+}
+
+/// Where the homeserver sends events, from the address the relay listens on
+pub fn appservice_url(host: &str) -> String {
+    let (address, port) = host.rsplit_once(':').unwrap_or((host, "80"));
+    // Listening on every address doesn't say which one the homeserver can reach
+    let address = match address {
+        "" | "0.0.0.0" | "::" | "[::]" => "localhost",
+        _ => address,
+    };
+    return format!("http://{}:{}", address, port);
+}
+
+fn token() -> String {
+    return rand::thread_rng().sample_iter(&Alphanumeric).take(TOKEN_LENGTH).map(char::from).collect();
+}
+
+// Single quoted yaml only needs quotes escaped, the regex's backslashes stay as they are
+fn yaml_quote(text: &str) -> String {
+    return format!("'{}'", text.replace('\'', "''"));
+}
+
+/// A new registration for the config, with new tokens
+pub fn generate(config: &Outer) -> String {
+    let prefix = puppet_prefix(config);
+    return format!(
+        "id: dc-appservice
+url: {}
+as_token: {}
+hs_token: {}
+sender_localpart: {}
+namespaces:
+  aliases: []
+  rooms: []
+  users:
+  - exclusive: true
+    regex: {}
+rate_limited: false
+protocols: []
+",
+        yaml_quote(&appservice_url(&config.host)),
+        token(),
+        token(),
+        yaml_quote(&prefix),
+        yaml_quote(&users_regex(&prefix, &config.server_name))
+    );
+}
+
+/// Checks the registration against the config. Returns warnings about what might be fine (a proxy in front of the
+/// relay changes its url), and fails on what can't work
+pub fn check(registration: &Registration, config: &Outer) -> anyhow::Result<Vec<String>> {
+    let prefix = puppet_prefix(config);
+    if config.puppet_prefix.is_some() && registration.sender_localpart != prefix {
+        return Err(anyhow!(
+            "sender_localpart \"{}\" isn't puppet_prefix \"{}\" from the config",
+            registration.sender_localpart,
+            prefix
+        ));
+    }
+
+    // Both the relay's own user and the puppets have to be in an exclusive namespace of the registration
+    let localpart = &registration.sender_localpart;
+    let users = [
+        format!("@{}bot:{}", localpart, config.server_name),
+        format!("@{}000000000000000000:{}", localpart, config.server_name),
+    ];
+    for user in users.iter() {
+        let mut covered = false;
+        for namespace in registration.namespaces.users.iter().filter(|namespace| namespace.exclusive) {
+            // Homeservers match the start of the id, like this
+            let regex = Regex::new(&format!("^(?:{})", namespace.regex))
+                .with_context(|| format!("The users regex \"{}\" isn't valid", namespace.regex))?;
+            covered = covered || regex.is_match(user);
+        }
+        if !covered {
+            return Err(anyhow!(
+                "No exclusive users namespace covers {}, it should have the regex '{}'",
+                user,
+                users_regex(localpart, &config.server_name)
+            ));
+        }
+    }
+
+    let mut warnings = Vec::new();
+    let port = config.host.rsplit_once(':').map(|(_, port)| port).unwrap_or("");
+    let url_port = registration.url.rsplit_once(':').map(|(_, port)| port.trim_end_matches('/')).unwrap_or("");
+    if port != url_port {
+        warnings.push(format!(
+            "url {} has another port than host {}, which is only right with a proxy in between",
+            registration.url, config.host
+        ));
+    }
+    return Ok(warnings);
+}
+
+pub fn load(path: &str) -> anyhow::Result<AppServiceRegistration> {
+    let registration = AppServiceRegistration::try_from_yaml_file(path);
+    if registration.is_err() {
+        return Err(anyhow!("Failed to read {}: {}", path, registration.err().unwrap()));
+    }
+    return Ok(registration.unwrap());
+}
+
+/// Loads the registration and checks it against the config, before anything starts
+pub fn verify(path: &str, config: &Outer) -> anyhow::Result<()> {
+    let registration = load(path)?;
+    let warnings = check(&registration, config).with_context(|| format!("{} doesn't match the config", path))?;
+    for warning in warnings.iter() {
+        println!("{}: {}", path, warning);
+    }
+    return Ok(());
+}
+
+/// Writes a new registration, but never over one the homeserver might already have
+pub fn write(path: &str, config: &Outer) -> anyhow::Result<()> {
+    if std::path::Path::new(path).exists() {
+        return Err(anyhow!("{} already exists, remove it first to generate a new one", path));
+    }
+    std::fs::write(path, generate(config)).with_context(|| format!("Failed to write {}", path))?;
+    return Ok(());
+}
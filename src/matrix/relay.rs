@@ -2,185 +2,706 @@ use std::{f32::consts::E, thread::panicking};
 
 use futures::future::Join;
 use matrix_sdk::{Client, room::Joined};
-use ruma::{RoomId, events::{room::message::{RoomMessageEventContent, Relation, MessageType}, relation::{InReplyTo, Replacement}}, EventId, OwnedEventId, MxcUri};
+use mime::Mime;
+use ruma::{RoomId, events::{room::{member::{MembershipState, RoomMemberEventContent}, message::{RoomMessageEventContent, Relation, MessageType, AudioInfo, AudioMessageEventContent, FileInfo, FileMessageEventContent, ImageMessageEventContent, VideoInfo, VideoMessageEventContent}, ImageInfo, MediaSource, ThumbnailInfo}, relation::{Annotation, InReplyTo, Replacement, Thread}, reaction::ReactionEventContent}, EventId, OwnedEventId, OwnedMxcUri, MxcUri, TransactionId, UInt, UserId};
 
-use crate::{chat_service::{Message, FullMessage, self}, CONFIG};
+use ruma::{api::client::state::get_state_events_for_key, events::StateEventType};
+use crate::{chat_service::{Message, FullMessage, FullReaction, Attachment, User, Store, self}, discord::{self, format::Mention}, error::{RelayError, RelayResult}, config::config, rooms};
 
 use super::bot::{BOT_REGISTRATION, BOT_APPSERVICE, BOT_CLIENT};
 
-async fn get_room_as_user(user: Client, room_id: &RoomId) -> Joined
+// The appservice's own bot, which is in every bridged room
+fn bot_client() -> RelayResult<Client>
 {
-    let client_local =  (*(BOT_CLIENT.lock().expect("Bot client is poisoned"))).clone();
-    let appservice_room = client_local.unwrap().get_joined_room(room_id);
-    appservice_room.unwrap().invite_user_by_id(user.user_id().unwrap()).await;
+    let client_local = (*(BOT_CLIENT.lock().expect("Bot client is poisoned"))).clone();
+    return client_local.ok_or(RelayError::Transport("the matrix bot isn't running yet".to_owned()));
+}
+
+fn bot_room(room_id: &RoomId) -> RelayResult<Joined>
+{
+    return bot_client()?
+        .get_joined_room(room_id)
+        .ok_or(RelayError::NotFound(format!("the bot isn't in room {}", room_id)));
+}
+
+// How often and how long send_notice waits for a room it just joined
+const NOTICE_JOIN_ATTEMPTS: u32 = 10;
+const NOTICE_JOIN_WAIT: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Has the bot join a room, which it has to be invited to unless the room is public
+pub async fn join_room(room_id: &str) -> RelayResult<()>
+{
+    let id: Box<RoomId> = RoomId::parse_box(room_id)?;
+    bot_client()?.join_room_by_id(id.as_ref()).await?;
+    return Ok(());
+}
+
+/// Sends a notice as the bot, joining the room first if it has to
+pub async fn send_notice(room_id: &str, text: &str) -> RelayResult<()>
+{
+    join_room(room_id).await?;
+    let id: Box<RoomId> = RoomId::parse_box(room_id)?;
+    // The bot only knows it is in the room once the homeserver sends it the join
+    for _ in 0..NOTICE_JOIN_ATTEMPTS {
+        let room = bot_client()?.get_joined_room(id.as_ref());
+        if room.is_some() {
+            room.unwrap().send(RoomMessageEventContent::notice_plain(text), None).await?;
+            return Ok(());
+        }
+        tokio::time::sleep(NOTICE_JOIN_WAIT).await;
+    }
+    return Err(RelayError::NotFound(format!("the bot isn't in room {}", room_id)));
+}
+
+async fn get_room_as_user(user: Client, room_id: &RoomId) -> RelayResult<Joined>
+{
+    let user_id = user.user_id().ok_or(RelayError::Transport("puppet isn't logged in".to_owned()))?.to_owned();
+    let room = user.get_joined_room(room_id);
+    if room.is_some() {
+        return Ok(room.unwrap());
+    }
+
+    bot_room(room_id)?.invite_user_by_id(&user_id).await?;
+    user.join_room_by_id(room_id).await?;
+    return user
+        .get_joined_room(room_id)
+        .ok_or(RelayError::NotFound(format!("{} couldn't join {}", user_id, room_id)));
+}
+
+/// Downloads matrix media as the relay, through the authenticated media api. Homeservers from before it only have
+/// the old unauthenticated one, which is tried when the new one isn't there
+pub async fn download_media(mxc: &str) -> RelayResult<(Vec<u8>, Option<String>)>
+{
+    let mxc: &MxcUri = mxc.into();
+    let (server_name, media_id) = mxc.parts().map_err(|_| RelayError::Invalid(format!("{} isn't an mxc uri", mxc)))?;
+    let registration_local = (*(BOT_REGISTRATION.lock().expect("Bot registration is poisoned"))).clone();
+    let token = registration_local.map(|registration| registration.as_token.clone());
+    let homeserver = config().homeserver_url.trim_end_matches("/").to_owned();
+
+    let url = format!("{}/_matrix/client/v1/media/download/{}/{}", homeserver, server_name, media_id);
+    let res = chat_service::download_authenticated(url, token.as_deref()).await;
+    if let Err(RelayError::NotFound(_)) = res {
+        let url = format!("{}/_matrix/media/v3/download/{}/{}", homeserver, server_name, media_id);
+        return chat_service::download_authenticated(url, token.as_deref()).await;
+    }
+    return res;
+}
 
-    user.join_room_by_id(room_id).await;
-    return user.get_joined_room(room_id).unwrap();
+pub fn puppet_user_id(discord_id: &str) -> String
+{
+    let registration_local = (*(BOT_REGISTRATION.lock().expect("Bot registration is poisoned"))).clone();
+    let localpart = registration_local.map(|registration| registration.sender_localpart).unwrap_or_default();
+    return format!("@{}{}:{}", localpart, discord_id, config().server_name);
 }
 
-async fn get_bot_user(user_id: String) -> Client
+/// (plain, html) mention of a discord user's puppet
+pub fn puppet_pill(discord_id: &str, display: &str) -> (String, String)
+{
+    let html = format!(
+        "<a href=\"https://matrix.to/#/{}\">{}</a>",
+        puppet_user_id(discord_id),
+        discord::format::escape_html(display)
+    );
+    return (display.to_owned(), html);
+}
+
+async fn get_bot_user(user_id: String) -> RelayResult<Client>
 {
     let registration_local = (*(BOT_REGISTRATION.lock().expect("Bot registration is poisoned"))).clone();
     let appservice_local = (*(BOT_APPSERVICE.lock().expect("Bot appservice is poisoned"))).clone();
+    if registration_local.is_none() || appservice_local.is_none() {
+        return Err(RelayError::Transport("the matrix appservice isn't running yet".to_owned()));
+    }
+    let appservice_local = appservice_local.unwrap();
 
     let relay_bot_name = format!(
         "{}{}",
-        registration_local
-            .as_ref()
-            .unwrap()
-            .sender_localpart
-            .clone(),
+        registration_local.unwrap().sender_localpart,
         user_id
     );
 
-    let res = appservice_local
-        .as_ref()
-        .unwrap()
-        .register_user(&relay_bot_name, None)
-        .await;    
+    // Fails when the puppet already exists, which is fine
+    appservice_local.register_user(&relay_bot_name, None).await.ok();
 
-    let user = appservice_local
-        .as_ref()
-        .unwrap()
-        .user(Some(&relay_bot_name))
-        .await.unwrap();
-    return user;
+    let user = appservice_local.user(Some(&relay_bot_name)).await?;
+    return Ok(user);
 }
 
-pub async fn relay_message(message: FullMessage) -> Message
+fn relayed_room(message: &FullMessage) -> Message
 {
     let mut out: Message = message.message.clone();
-    for mroom in CONFIG.room.iter() {
-        if mroom.discord == message.message.room_id
-        {
-            out = Message {
-                service: "matrix".to_owned(),
-                server_id: "".to_owned(),
-                room_id: mroom.matrix.clone(),
-                id: message.message.id.clone()
-            };
+    // Messages in threads are relayed to the room of the thread's channel
+    let mut room_id = message.message.room_id.clone();
+    if message.thread.is_some() {
+        room_id = message.thread.as_ref().unwrap().room_id.clone();
+    }
+
+    let mroom = rooms::by_discord(&room_id);
+    if mroom.is_some() {
+        out = Message {
+            service: "matrix".to_owned(),
+            server_id: "".to_owned(),
+            room_id: mroom.unwrap().matrix,
+            id: message.message.id.clone()
+        };
+    }
+    return out;
+}
+
+const DEFAULT_DISPLAYNAME_TEMPLATE: &str = "{nick} ({username})";
+
+/// Fills in the display name template, nick is the name the user goes by where the puppet is shown
+pub fn puppet_display_name(template: &str, nick: &str, user: &User) -> String
+{
+    let mut out = "".to_owned();
+    let mut rest = template;
+    while let Some(start) = rest.find("{") {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = rest.find("}");
+        if end.is_none() {
             break;
         }
+        let end = end.unwrap();
+        let value = match &rest[1..end] {
+            "nick" => nick,
+            "username" => user.name.as_str(),
+            "tag" => user.tag.as_str(),
+            "id" => user.id.as_str(),
+            _ => &rest[..end + 1],
+        };
+        out.push_str(value);
+        rest = &rest[end + 1..];
     }
+    out.push_str(rest);
+    return out;
+}
 
-    let user = get_bot_user(message.user.id).await;
+fn display_name_template() -> String
+{
+    return config().displayname_template.clone().unwrap_or(DEFAULT_DISPLAYNAME_TEMPLATE.to_owned());
+}
 
-    let changed_name = user
-        .account()
-        .set_display_name(Some(format!("{} ({})", &message.user.display.clone(), &message.user.tag.clone()).as_str()))
-        .await
-        .is_ok();
+// The global profile uses the username, nicknames are set per room by update_room_name
+async fn update_profile(store: &Store, user: &Client, message: &FullMessage) -> RelayResult<()>
+{
+    let name = puppet_display_name(&display_name_template(), &message.user.name, &message.user);
+    if store.puppet_name(&message.user.id, "").await? != Some(name.clone()) {
+        user.account().set_display_name(Some(name.as_str())).await?;
+        store.set_puppet_name(&message.user.id, "", &name).await?;
+        store.clear_puppet_room_names(&message.user.id).await?;
+    }
 
     if message.user.avatar.is_some() {
-        //user.account().set_avatar_url(uri);
+        update_avatar(store, user, &message.user.id, message.user.avatar.clone().unwrap()).await?;
+    }
+    return Ok(());
+}
+
+// Avatars are only uploaded once per hash, and only set when the user's hash has changed
+async fn update_avatar(store: &Store, user: &Client, discord_id: &str, hash: String) -> RelayResult<()>
+{
+    if store.puppet_avatar(discord_id).await? == Some(hash.clone()) {
+        return Ok(());
+    }
+
+    let mut mxc = store.avatar_mxc(&hash).await?;
+    if mxc.is_none() {
+        let (data, content_type) = chat_service::download(discord::bot::avatar_url(discord_id, &hash)).await?;
+        let content_type = content_type
+            .and_then(|content_type| content_type.parse::<Mime>().ok())
+            .unwrap_or(mime::IMAGE_PNG);
+
+        let uri = user.media().upload(&content_type, data).await?.content_uri.to_string();
+        store.create_avatar(&hash, &uri).await?;
+        mxc = Some(uri);
+    }
+
+    let uri: OwnedMxcUri = mxc.unwrap().into();
+    user.account().set_avatar_url(Some(&uri)).await?;
+    store.set_puppet_avatar(discord_id, &hash).await?;
+    store.clear_puppet_room_names(discord_id).await?;
+    return Ok(());
+}
+
+// Sets the puppet's name in just this room, so nicknames from different guilds don't clash
+async fn update_room_name(store: &Store, room: &Joined, message: &FullMessage) -> RelayResult<()>
+{
+    let name = puppet_display_name(&display_name_template(), &message.user.display, &message.user);
+    let room_id = room.room_id().to_string();
+    if store.puppet_name(&message.user.id, &room_id).await? == Some(name.clone()) {
+        return Ok(());
+    }
+
+    // The member event replaces the old one, so the avatar has to be kept
+    let mut content = RoomMemberEventContent::new(MembershipState::Join);
+    content.displayname = Some(name.clone());
+    let avatar_hash = store.puppet_avatar(&message.user.id).await?;
+    if avatar_hash.is_some() {
+        content.avatar_url = store.avatar_mxc(&avatar_hash.unwrap()).await?.map(|mxc| OwnedMxcUri::from(mxc));
+    }
+
+    room.send_state_event_for_key(room.own_user_id(), content).await?;
+    store.set_puppet_name(&message.user.id, &room_id, &name).await?;
+    return Ok(());
+}
+
+// A puppet with an outdated name or avatar is better than a message that wasn't relayed
+async fn update_puppet(store: &Store, user: &Client, room: &Joined, message: &FullMessage)
+{
+    let res = update_profile(store, user, message).await;
+    if res.is_err() {
+        println!("Failed to update profile of {}: {}", message.user.id, res.err().unwrap());
+    }
+    let res = update_room_name(store, room, message).await;
+    if res.is_err() {
+        println!("Failed to set display name of {} in {}: {}", message.user.id, room.room_id(), res.err().unwrap());
+    }
+}
+
+// Builds the event for an uploaded file, thumbnail is the uploaded thumbnail's (uri, content type, size)
+fn attachment_message_type(attachment: &Attachment, content_type: &Mime, size: usize, uri: OwnedMxcUri, thumbnail: Option<(OwnedMxcUri, Mime, usize)>) -> MessageType
+{
+    let size = UInt::new(size as u64);
+    let width = attachment.width.and_then(UInt::new);
+    let height = attachment.height.and_then(UInt::new);
+    let mimetype = Some(content_type.to_string());
+
+    let mut thumbnail_info: Option<Box<ThumbnailInfo>> = None;
+    let mut thumbnail_source: Option<MediaSource> = None;
+    if thumbnail.is_some() {
+        let (thumbnail_uri, thumbnail_type, thumbnail_size) = thumbnail.unwrap();
+        let mut info = ThumbnailInfo::new();
+        info.mimetype = Some(thumbnail_type.to_string());
+        info.size = UInt::new(thumbnail_size as u64);
+        thumbnail_info = Some(Box::new(info));
+        thumbnail_source = Some(MediaSource::Plain(thumbnail_uri));
+    }
+
+    let body = attachment.filename.clone();
+    if content_type.type_() == mime::IMAGE {
+        let mut info = ImageInfo::new();
+        (info.mimetype, info.size, info.width, info.height) = (mimetype, size, width, height);
+        (info.thumbnail_info, info.thumbnail_source) = (thumbnail_info, thumbnail_source);
+        return MessageType::Image(ImageMessageEventContent::plain(body, uri, Some(Box::new(info))));
+    }
+    if content_type.type_() == mime::VIDEO {
+        let mut info = VideoInfo::new();
+        (info.mimetype, info.size, info.width, info.height) = (mimetype, size, width, height);
+        (info.thumbnail_info, info.thumbnail_source) = (thumbnail_info, thumbnail_source);
+        return MessageType::Video(VideoMessageEventContent::plain(body, uri, Some(Box::new(info))));
+    }
+    if content_type.type_() == mime::AUDIO {
+        let mut info = AudioInfo::new();
+        (info.mimetype, info.size) = (mimetype, size);
+        return MessageType::Audio(AudioMessageEventContent::plain(body, uri, Some(Box::new(info))));
+    }
+    let mut info = FileInfo::new();
+    (info.mimetype, info.size) = (mimetype, size);
+    (info.thumbnail_info, info.thumbnail_source) = (thumbnail_info, thumbnail_source);
+    return MessageType::File(FileMessageEventContent::plain(body, uri, Some(Box::new(info))));
+}
+
+// The matrix thread root for a message sent in a discord thread, the thread is bridged the first time it is used
+async fn find_thread_root(store: &Store, message: &FullMessage, room: &Joined) -> RelayResult<Option<OwnedEventId>>
+{
+    if message.thread.is_none() {
+        return Ok(None);
+    }
+    let thread = message.thread.clone().unwrap();
+
+    let mut root = store.thread_root(thread.clone()).await?;
+    if root.is_none() {
+        // Threads started from a message have the same id as it
+        let starter = Message {
+            service: "discord".to_owned(),
+            server_id: thread.server_id.clone(),
+            room_id: thread.room_id.clone(),
+            id: thread.id.clone(),
+        };
+        root = store.message_counterpart(starter, "matrix").await?;
+
+        if root.is_none() {
+            // Nothing to hang the thread off, so it is started with its name
+            let name = discord::bot::channel_name(&thread.id).await;
+            let content = RoomMessageEventContent::notice_plain(format!("Thread: {}", name));
+            let res = room.send(content, None).await?;
+            root = Some(Message {
+                service: "matrix".to_owned(),
+                server_id: "".to_owned(),
+                room_id: room.room_id().to_string(),
+                id: res.event_id.to_string(),
+            });
+        }
+        store.create_thread(thread, root.clone().unwrap()).await?;
+    }
+    return Ok(Some(EventId::parse(root.unwrap().id)?));
+}
+
+// The bridged room a discord message goes to, or an error when its channel isn't bridged
+async fn target_room(message: &FullMessage) -> RelayResult<(Message, Client, Joined)>
+{
+    let out: Message = relayed_room(message);
+    if out.service != "matrix" {
+        return Err(RelayError::NotFound(format!("no room is bridged to channel {}", message.message.room_id)));
     }
 
+    let user = get_bot_user(message.user.id.clone()).await?;
+    let id: Box<RoomId> = RoomId::parse_box(out.room_id.clone().as_ref())?;
+    let room = get_room_as_user(user.clone(), id.as_ref()).await?;
+    return Ok((out, user, room));
+}
+
+// Plain and html body of a discord message
+fn message_body(message: &FullMessage) -> (String, String)
+{
+    // Nicknames of the pinged users come with the message, anyone else is looked up from discord's cache
+    let resolve = |mention: &Mention| -> Option<(String, String)> {
+        if let Mention::User(id) = mention {
+            let mentioned = message.mentions.iter().find(|user| user.id == *id);
+            if mentioned.is_some() {
+                return Some(puppet_pill(id, &mentioned.unwrap().display));
+            }
+        }
+        return discord::bot::resolve_mention(mention);
+    };
+    return discord::format::discord_to_matrix(&message.content, &resolve);
+}
+
+/// The transaction id of an outbox delivery to matrix. Every attempt uses the same one, so a retry after the event was
+/// sent gets the event back from the homeserver instead of sending it again
+pub fn transaction_id(source: &Message, part: &str) -> String
+{
+    return format!("relay_{}_{}_{}", source.service, source.id, part);
+}
+
+/// Sends a discord message's text. txn_id is given by the outbox, so its retries aren't sent twice
+pub async fn relay_message(store: &Store, message: FullMessage, txn_id: Option<String>) -> RelayResult<Message>
+{
+    let (mut out, user, room) = target_room(&message).await?;
+    update_puppet(store, &user, &room, &message).await;
+
+    let (body, html_body) = message_body(&message);
+    let mut content = RoomMessageEventContent::text_html(body, html_body);
 
-    let id: Box<RoomId> = RoomId::parse_box(out.room_id.clone().as_ref()).unwrap();
+    let mut mentioned_ids: Vec<String> = message.mentions.iter().map(|user| puppet_user_id(&user.id)).collect();
 
-    let room = get_room_as_user(user, id.as_ref()).await;
-    let content = RoomMessageEventContent::text_html(message.content.clone(), markdown::to_html(&message.content.clone()));
+    // A message that lost its thread is still worth sending
+    let thread_root = find_thread_root(store, &message, &room).await;
+    if thread_root.is_err() {
+        println!("Failed to find thread root for {}: {}", message.message.id, thread_root.as_ref().err().unwrap());
+    }
+    let thread_root = thread_root.unwrap_or(None);
 
-    let mut reply_id: String = "".to_owned();
     if message.reply.is_some() {
         let reply_msg = *message.reply.unwrap();
+        let reply_target = store.message_counterpart(reply_msg, "matrix").await?;
+
+        if reply_target.is_some() {
+            let reply_id = EventId::parse(reply_target.unwrap().id)?;
 
-        let relayed_messages = chat_service::message_relays(reply_msg.clone());
-        
-        if relayed_messages.len() > 0 {
-            for msg in relayed_messages.iter() {
-                if msg.service == "matrix" {
-                    reply_id = msg.id.clone();
+            // Whoever wrote the message being replied to gets notified, like on discord
+            let reply_event = room.event(&reply_id).await;
+            if reply_event.is_ok() {
+                let reply_json: serde_json::Value = serde_json::from_str(&reply_event.unwrap().event.json().to_string()).unwrap_or_default();
+                let sender = reply_json["sender"].as_str();
+                if sender.is_some() && !mentioned_ids.contains(&sender.unwrap().to_owned()) {
+                    mentioned_ids.push(sender.unwrap().to_owned());
                 }
             }
+
+            if thread_root.is_some() {
+                content.relates_to = Some(Relation::Thread(Thread::reply(thread_root.clone().unwrap(), reply_id)));
+            } else {
+                content.relates_to = Some(Relation::Reply { in_reply_to: InReplyTo::new(reply_id) });
+            }
         }
-        else {
-            let origin_message = chat_service::message_origin(reply_msg.clone());
-            if origin_message.is_some() {
-                reply_id = origin_message.unwrap().id;
+    }
+
+    if content.relates_to.is_none() && thread_root.is_some() {
+        let root = thread_root.unwrap();
+        content.relates_to = Some(Relation::Thread(Thread::plain(root.clone(), root)));
+    }
+
+    out.id = send_with_mentions(&room, content, mentioned_ids, txn_id).await?.to_string();
+    return Ok(out);
+}
+
+/// Uploads the attachment to the media repo and sends it as its own m.image/m.video/m.audio/m.file event.
+pub async fn relay_attachment(store: &Store, message: FullMessage, attachment: Attachment, txn_id: Option<String>) -> RelayResult<Message>
+{
+    let (mut out, user, room) = target_room(&message).await?;
+    update_puppet(store, &user, &room, &message).await;
+
+    let (data, server_content_type) = chat_service::download(attachment.url.clone()).await?;
+
+    let content_type: Mime = attachment.content_type.clone()
+        .or(server_content_type)
+        .and_then(|content_type| content_type.parse::<Mime>().ok())
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    let size = data.len();
+
+    let uri = user.media().upload(&content_type, data).await?.content_uri;
+
+    // The file is still sent without a thumbnail if that fails
+    let mut thumbnail: Option<(OwnedMxcUri, Mime, usize)> = None;
+    if attachment.thumbnail_url.is_some() {
+        let thumbnail_download = chat_service::download(attachment.thumbnail_url.clone().unwrap()).await;
+        if thumbnail_download.is_ok() {
+            let (thumbnail_data, thumbnail_type) = thumbnail_download.unwrap();
+            let thumbnail_type = thumbnail_type
+                .and_then(|content_type| content_type.parse::<Mime>().ok())
+                .unwrap_or(mime::IMAGE_JPEG);
+            let thumbnail_size = thumbnail_data.len();
+            let thumbnail_upload = user.media().upload(&thumbnail_type, thumbnail_data).await;
+            if thumbnail_upload.is_ok() {
+                thumbnail = Some((thumbnail_upload.unwrap().content_uri, thumbnail_type, thumbnail_size));
             }
         }
+    }
 
+    let msgtype = attachment_message_type(&attachment, &content_type, size, uri, thumbnail);
+    let mut content = RoomMessageEventContent::new(msgtype);
+    let thread_root = find_thread_root(store, &message, &room).await.unwrap_or(None);
+    if thread_root.is_some() {
+        let root = thread_root.unwrap();
+        content.relates_to = Some(Relation::Thread(Thread::plain(root.clone(), root)));
     }
 
-    if reply_id == "" {
-        let res = room.send(content, None).await;
-        out.id = res.unwrap().event_id.to_string();
+    out.id = send_with_mentions(&room, content, Vec::new(), txn_id).await?.to_string();
+    return Ok(out);
+}
+
+/// Body of an edit event, for clients that don't support edits. The new content itself goes in m.new_content
+pub fn edit_fallback(body: &str, html_body: &str) -> (String, String)
+{
+    return (format!("* {}", body), format!("* {}", html_body));
+}
+
+/// What editing a discord message does to the matrix events it was relayed as
+pub struct EditPlan {
+    // The text event, to be replaced with the new text
+    pub edit: Option<Message>,
+    // Attachments that were removed from the message, and the text event if the text was removed
+    pub redact: Vec<Message>,
+    // Text added to a message that only had attachments, it's sent the way it would have been originally
+    pub send: bool,
+}
+
+/// Decides what an edit does from the relayed events that are still there, each with its content, the new text and
+/// the file names of the attachments the message still has
+pub fn plan_edit(events: &[(Message, serde_json::Value)], content: &str, filenames: &[String]) -> EditPlan
+{
+    let mut plan = EditPlan { edit: None, redact: Vec::new(), send: false };
+    for (msg, event_content) in events.iter() {
+        let msgtype = event_content["msgtype"].as_str().unwrap_or_default();
+        if msgtype == "m.text" || msgtype == "m.notice" || msgtype == "m.emote" {
+            plan.edit = Some(msg.clone());
+            continue;
+        }
+
+        // Attachment events have the file name as their body
+        let filename = event_content["body"].as_str().unwrap_or_default();
+        if !filenames.iter().any(|name| name == filename) {
+            plan.redact.push(msg.clone());
+        }
     }
-    else {
-        let res = reply_to_message(room, EventId::parse(reply_id).unwrap(), content).await;
-        out.id = res.to_string();
+
+    if content == "" {
+        // Only the attachments are left
+        if plan.edit.is_some() {
+            plan.redact.push(plan.edit.take().unwrap());
+        }
+    } else if plan.edit.is_none() {
+        plan.send = true;
     }
-    //let member = room.get_member(&user.user_id().unwrap()).await.unwrap().unwrap().
-    return out;
+    return plan;
 }
 
-pub async fn edit_message(message: FullMessage)
+/// The text of a discord message and each of its attachments are separate matrix events. The text event is edited
+/// (or sent, if the message had none), and attachments that were removed from the message are redacted
+pub async fn edit_message(store: &Store, message: FullMessage) -> RelayResult<()>
 {
-    let html_body = markdown::to_html(&message.content.clone());
-    let body = message.content.clone();
-    let content = RoomMessageEventContent::text_html(body.clone(), html_body.clone());
-    let relayed_messages = chat_service::message_relays(message.message);
-    let user = get_bot_user(message.user.id).await;
-    
+    let relayed_messages: Vec<Message> = store.message_relays(message.message.clone()).await?
+        .into_iter()
+        .filter(|msg| msg.service == "matrix")
+        .collect();
+    if relayed_messages.is_empty() {
+        return Ok(());
+    }
+
+    let user = get_bot_user(message.user.id.clone()).await?;
+    let id: Box<RoomId> = RoomId::parse_box(relayed_messages[0].room_id.as_ref())?;
+    let room = get_room_as_user(user.clone(), id.as_ref()).await?;
+
+    let mut events: Vec<(Message, serde_json::Value)> = Vec::new();
     for msg in relayed_messages.iter() {
-        if msg.service == "matrix" {
-            let id: Box<RoomId> = RoomId::parse_box(msg.room_id.clone().as_ref()).unwrap();
-            let room = get_room_as_user(user.clone(), id.as_ref()).await;
-            let event_id = EventId::parse(msg.id.clone()).unwrap();
-
-            let replacement = Replacement::new(
-                event_id,
-                MessageType::text_html(body.clone(), html_body.clone())
-            );
-            let mut edited_content = content.clone();
-            edited_content.relates_to = Some(Relation::Replacement(replacement));
-            room.timeline().await.send(edited_content.into(), None).await;
+        let event_id = EventId::parse(msg.id.clone())?;
+        // Already redacted or otherwise gone, there's nothing left to edit
+        let event = room.event(&event_id).await;
+        if event.is_err() {
+            continue;
+        }
+        let event_json: serde_json::Value = serde_json::from_str(&event.unwrap().event.json().to_string()).unwrap_or_default();
+        events.push((msg.clone(), event_json["content"].clone()));
+    }
+
+    let filenames: Vec<String> = message.attachments.iter().map(|attachment| attachment.filename.clone()).collect();
+    let plan = plan_edit(&events, &message.content, &filenames);
+    for msg in plan.redact.into_iter() {
+        redact(msg.clone()).await?;
+        store.delete_relayed_message(msg).await?;
+    }
+
+    if plan.send {
+        let source = message.message.clone();
+        // Not a retry of the original send, which could have had text that was redacted since
+        let out = relay_message(store, message, None).await?;
+        store.create_message(source, out).await?;
+        return Ok(());
+    }
+    if plan.edit.is_none() {
+        return Ok(());
+    }
+    let text_event = EventId::parse(plan.edit.unwrap().id)?;
+
+    // The reply and thread relations stay on the original event, an edit can't change them
+    let (body, html_body) = message_body(&message);
+    let (fallback, html_fallback) = edit_fallback(&body, &html_body);
+    let mut content = RoomMessageEventContent::text_html(fallback, html_fallback);
+    content.relates_to = Some(Relation::Replacement(Replacement::new(
+        text_event,
+        MessageType::text_html(body, html_body)
+    )));
+    room.send(content, None).await?;
+    return Ok(());
+}
+
+async fn redact(msg: Message) -> RelayResult<()>
+{
+    let id: Box<RoomId> = RoomId::parse_box(msg.room_id.clone().as_ref())?;
+    let event_id = EventId::parse_box(msg.id)?;
+    bot_room(id.as_ref())?.redact(&event_id, None, None).await?;
+    return Ok(());
+}
+
+// Moderators' puppets redact as themselves, so matrix shows who removed the message. A puppet without power in the room
+// couldn't, and neither could one whose redaction is refused, so those go through the bot
+async fn redact_deletion(msg: Message, deleted_by: Option<&User>, reason: Option<&str>) -> RelayResult<()>
+{
+    let id: Box<RoomId> = RoomId::parse_box(msg.room_id.clone().as_ref())?;
+    let event_id = EventId::parse_box(msg.id.clone())?;
+
+    if deleted_by.is_some() {
+        let res = redact_as_puppet(deleted_by.unwrap(), id.as_ref(), &event_id, reason).await;
+        match res {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(err) => println!("Failed to redact {} as {}, using the bot: {}", msg.id, deleted_by.unwrap().id, err),
         }
     }
+
+    bot_room(id.as_ref())?.redact(&event_id, reason, None).await?;
+    return Ok(());
 }
 
-pub async fn delete_message(message: Message)
+/// Whether a user can redact other people's events, from the content of a room's m.room.power_levels
+pub fn can_redact(power_levels: &serde_json::Value, user_id: &str) -> bool
 {
+    // The defaults of the spec, for whatever the room leaves out
+    let redact = power_levels["redact"].as_i64().unwrap_or(50);
+    let users_default = power_levels["users_default"].as_i64().unwrap_or(0);
+    let level = power_levels["users"][user_id].as_i64().unwrap_or(users_default);
+    return level >= redact;
+}
 
-    let relayed_messages = chat_service::message_relays(message.clone());
-    if relayed_messages.len() > 0 {
-        for msg in relayed_messages {
-            if msg.service == "matrix" {
-                let id: Box<RoomId> = RoomId::parse_box(msg.room_id.clone().as_ref()).unwrap();
-                
-                let client_local =  (*(BOT_CLIENT.lock().expect("Bot client is poisoned"))).clone();
-                let appservice_room = client_local.unwrap().get_joined_room(id.as_ref());
-            
-                let event_id = EventId::parse_box(msg.id).unwrap();
-                let event_id_ref = &(*event_id);
-                appservice_room.unwrap().redact(event_id_ref, None, None).await;
-            }
+// False when the puppet can't redact other people's messages in the room
+async fn redact_as_puppet(deleted_by: &User, room_id: &RoomId, event_id: &EventId, reason: Option<&str>) -> RelayResult<bool>
+{
+    let puppet_id = UserId::parse(puppet_user_id(&deleted_by.id))?;
+    let member = bot_room(room_id)?.get_member(&puppet_id).await?;
+    if member.is_none() {
+        return Ok(false);
+    }
+    let request = get_state_events_for_key::v3::Request::new(room_id.to_owned(), StateEventType::RoomPowerLevels, "".to_owned());
+    let power_levels: serde_json::Value = serde_json::from_str(bot_client()?.send(request, None).await?.content.json().get())?;
+    if !can_redact(&power_levels, puppet_id.as_str()) {
+        return Ok(false);
+    }
+
+    let user = get_bot_user(deleted_by.id.clone()).await?;
+    let room = get_room_as_user(user, room_id).await?;
+    room.redact(event_id, reason, None).await?;
+    return Ok(true);
+}
+
+pub async fn delete_message(store: &Store, message: Message, deleted_by: Option<&User>, reason: Option<&str>) -> RelayResult<()>
+{
+    let mut targets: Vec<Message> = store.message_relays(message.clone()).await?
+        .into_iter()
+        .filter(|msg| msg.service == "matrix")
+        .collect();
+
+    let origin_message = store.message_origin(message.clone()).await?;
+    if origin_message.is_some() && origin_message.as_ref().unwrap().service == "matrix" {
+        targets.push(origin_message.unwrap());
+    }
+
+    // Every part is tried, the first failure is returned
+    let mut result = Ok(());
+    for msg in targets {
+        let res = redact_deletion(msg, deleted_by, reason).await;
+        if res.is_err() && result.is_ok() {
+            result = res;
         }
     }
+    return result;
+}
+
+// None when the message isn't bridged
+pub async fn relay_reaction(store: &Store, reaction: FullReaction) -> RelayResult<Option<Message>>
+{
+    let target = store.message_counterpart(reaction.message.clone(), "matrix").await?;
+    if target.is_none() {
+        return Ok(None);
+    }
+    let target = target.unwrap();
 
-    let origin_message = chat_service::message_origin(message.clone());
-    if origin_message.is_some() && origin_message.clone().unwrap().clone().service == "matrix" {
-        let id: Box<RoomId> = RoomId::parse_box(origin_message.clone().unwrap().room_id.clone().as_ref()).unwrap();
-                
-        let client_local =  (*(BOT_CLIENT.lock().expect("Bot client is poisoned"))).clone();
-        let appservice_room = client_local.unwrap().get_joined_room(id.as_ref());
-    
-        let event_id = EventId::parse_box(origin_message.clone().unwrap().id).unwrap();
-        let event_id_ref = &(*event_id);
-        appservice_room.unwrap().redact(event_id_ref, None, None).await;
+    let user = get_bot_user(reaction.user.id.clone()).await?;
+    let id: Box<RoomId> = RoomId::parse_box(target.room_id.clone().as_ref())?;
+    let room = get_room_as_user(user, id.as_ref()).await?;
+
+    let event_id = EventId::parse(target.id.clone())?;
+    let content = ReactionEventContent::new(Annotation::new(event_id, reaction.emoji.clone()));
+    let res = room.send(content, None).await?;
+
+    return Ok(Some(Message {
+        service: "matrix".to_owned(),
+        server_id: "".to_owned(),
+        room_id: target.room_id,
+        id: res.event_id.to_string(),
+    }));
+}
+
+pub async fn delete_reaction(store: &Store, reaction: Message) -> RelayResult<()>
+{
+    for relayed in store.reaction_relays(reaction).await? {
+        if relayed.service != "matrix" {
+            continue;
+        }
+        redact(relayed).await?;
     }
+    return Ok(());
 }
 
-async fn reply_to_message(room: Joined, event_id: OwnedEventId, content: RoomMessageEventContent) -> OwnedEventId
+// m.mentions isn't in our version of ruma, so it is added to the json by hand
+async fn send_with_mentions(room: &Joined, content: RoomMessageEventContent, user_ids: Vec<String>, txn_id: Option<String>) -> RelayResult<OwnedEventId>
 {
-    let replacement = InReplyTo::new(
-        event_id
-    );
-    let mut reply_content = content;
-    reply_content.relates_to = Some(Relation::Reply { in_reply_to: replacement } );
-    let timeline = room.timeline().await;
+    let mut content_json = serde_json::to_value(&content)?;
+    content_json["m.mentions"] = serde_json::json!({ "user_ids": user_ids });
 
-    let out = timeline.send(reply_content.into(), None).await;
-    return timeline.latest_event().unwrap().as_local().unwrap().event_id().unwrap().to_owned();
-}
\ No newline at end of file
+    let txn_id: Option<&TransactionId> = txn_id.as_deref().map(|id| id.into());
+    let res = room.send_raw(content_json, "m.room.message", txn_id).await?;
+    return Ok(res.event_id);
+}
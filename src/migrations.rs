@@ -0,0 +1,338 @@
+// The database schema, as a list of migrations. The version the database is at is kept in sqlite's user_version
+// (or the schema_version table on postgres), so on startup only the migrations after it are applied.
+// Never edit a migration once it has been released, add a new one.
+
+use rusqlite::Connection;
+
+use crate::error::{RelayError, RelayResult};
+
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+    // The same change for postgres
+    pub postgres: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    // Databases from before migrations existed already have these tables (at version 0), hence IF NOT EXISTS
+    Migration {
+        version: 1,
+        name: "initial schema",
+        sql: "
+        CREATE TABLE IF NOT EXISTS messages (
+            id  INTEGER PRIMARY KEY,
+            service_org TEXT NOT NULL,
+            server_id_org   TEXT NOT NULL,
+            room_id_org TEXT NOT NULL,
+            id_org  TEXT NOT NULL,
+            service_out TEXT NOT NULL,
+            server_id_out   TEXT NOT NULL,
+            room_id_out TEXT NOT NULL,
+            id_out  TEXT NOT NULL UNIQUE
+        );
+
+        -- Same layout as messages, but discord reactions have no id of their own so one is built from the message, user and emoji
+        CREATE TABLE IF NOT EXISTS reactions (
+            id  INTEGER PRIMARY KEY,
+            service_org TEXT NOT NULL,
+            server_id_org   TEXT NOT NULL,
+            room_id_org TEXT NOT NULL,
+            id_org  TEXT NOT NULL,
+            service_out TEXT NOT NULL,
+            server_id_out   TEXT NOT NULL,
+            room_id_out TEXT NOT NULL,
+            id_out  TEXT NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS threads (
+            id  INTEGER PRIMARY KEY,
+            service_org TEXT NOT NULL,
+            server_id_org   TEXT NOT NULL,
+            room_id_org TEXT NOT NULL,
+            id_org  TEXT NOT NULL UNIQUE,
+            service_out TEXT NOT NULL,
+            server_id_out   TEXT NOT NULL,
+            room_id_out TEXT NOT NULL,
+            id_out  TEXT NOT NULL UNIQUE
+        );
+
+        -- Uploaded discord avatars, so each one is only uploaded once
+        CREATE TABLE IF NOT EXISTS avatars (
+            hash    TEXT PRIMARY KEY,
+            mxc TEXT NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS puppets (
+            user_id TEXT PRIMARY KEY,
+            avatar_hash TEXT
+        );
+
+        -- Display names set on puppets, room_id is empty for the global display name
+        CREATE TABLE IF NOT EXISTS puppet_names (
+            user_id TEXT NOT NULL,
+            room_id TEXT NOT NULL,
+            displayname TEXT NOT NULL,
+            PRIMARY KEY (user_id, room_id)
+        );
+
+        -- Outbound deliveries, kept until they are delivered. Dead ones stay around so they can be inspected
+        CREATE TABLE IF NOT EXISTS outbox (
+            id  INTEGER PRIMARY KEY,
+            destination TEXT NOT NULL,
+            payload TEXT NOT NULL,
+            attempts    INTEGER NOT NULL,
+            next_attempt    INTEGER NOT NULL,
+            last_error  TEXT,
+            dead    INTEGER NOT NULL
+        );
+        ",
+        postgres: "
+        CREATE TABLE messages (
+            id  BIGSERIAL PRIMARY KEY,
+            service_org TEXT NOT NULL,
+            server_id_org   TEXT NOT NULL,
+            room_id_org TEXT NOT NULL,
+            id_org  TEXT NOT NULL,
+            service_out TEXT NOT NULL,
+            server_id_out   TEXT NOT NULL,
+            room_id_out TEXT NOT NULL,
+            id_out  TEXT NOT NULL UNIQUE
+        );
+
+        CREATE TABLE reactions (
+            id  BIGSERIAL PRIMARY KEY,
+            service_org TEXT NOT NULL,
+            server_id_org   TEXT NOT NULL,
+            room_id_org TEXT NOT NULL,
+            id_org  TEXT NOT NULL,
+            service_out TEXT NOT NULL,
+            server_id_out   TEXT NOT NULL,
+            room_id_out TEXT NOT NULL,
+            id_out  TEXT NOT NULL
+        );
+
+        CREATE TABLE threads (
+            id  BIGSERIAL PRIMARY KEY,
+            service_org TEXT NOT NULL,
+            server_id_org   TEXT NOT NULL,
+            room_id_org TEXT NOT NULL,
+            id_org  TEXT NOT NULL UNIQUE,
+            service_out TEXT NOT NULL,
+            server_id_out   TEXT NOT NULL,
+            room_id_out TEXT NOT NULL,
+            id_out  TEXT NOT NULL UNIQUE
+        );
+
+        CREATE TABLE avatars (
+            hash    TEXT PRIMARY KEY,
+            mxc TEXT NOT NULL
+        );
+
+        CREATE TABLE puppets (
+            user_id TEXT PRIMARY KEY,
+            avatar_hash TEXT
+        );
+
+        CREATE TABLE puppet_names (
+            user_id TEXT NOT NULL,
+            room_id TEXT NOT NULL,
+            displayname TEXT NOT NULL,
+            PRIMARY KEY (user_id, room_id)
+        );
+
+        CREATE TABLE outbox (
+            id  BIGSERIAL PRIMARY KEY,
+            destination TEXT NOT NULL,
+            payload TEXT NOT NULL,
+            attempts    BIGINT NOT NULL,
+            next_attempt    BIGINT NOT NULL,
+            last_error  TEXT,
+            dead    BOOLEAN NOT NULL
+        );
+        ",
+    },
+    // Every relay looks messages up by one side or the other, which scanned the whole table until now
+    Migration {
+        version: 2,
+        name: "lookup indexes and message timestamps",
+        sql: "
+        CREATE INDEX IF NOT EXISTS messages_origin ON messages (service_org, room_id_org, id_org);
+        CREATE INDEX IF NOT EXISTS messages_relay ON messages (service_out, room_id_out, id_out);
+        CREATE INDEX IF NOT EXISTS reactions_origin ON reactions (service_org, room_id_org, id_org);
+        CREATE INDEX IF NOT EXISTS reactions_relay ON reactions (service_out, room_id_out, id_out);
+
+        -- Unix time in seconds the message was relayed at, empty for messages relayed before this migration
+        ALTER TABLE messages ADD COLUMN created_at INTEGER;
+        ",
+        postgres: "
+        CREATE INDEX messages_origin ON messages (service_org, room_id_org, id_org);
+        CREATE INDEX messages_relay ON messages (service_out, room_id_out, id_out);
+        CREATE INDEX reactions_origin ON reactions (service_org, room_id_org, id_org);
+        CREATE INDEX reactions_relay ON reactions (service_out, room_id_out, id_out);
+
+        ALTER TABLE messages ADD COLUMN created_at BIGINT;
+        ",
+    },
+    Migration {
+        version: 3,
+        name: "bridged rooms",
+        sql: "
+        -- Rooms from the config are copied in on startup, the rest are bridged with the bridge commands
+        CREATE TABLE rooms (
+            discord TEXT PRIMARY KEY,
+            discord_guild   TEXT NOT NULL,
+            matrix  TEXT NOT NULL UNIQUE,
+            webhook TEXT NOT NULL,
+            max_upload_size INTEGER
+        );
+        ",
+        postgres: "
+        CREATE TABLE rooms (
+            discord TEXT PRIMARY KEY,
+            discord_guild   TEXT NOT NULL,
+            matrix  TEXT NOT NULL UNIQUE,
+            webhook TEXT NOT NULL,
+            max_upload_size BIGINT
+        );
+        ",
+    },
+    Migration {
+        version: 4,
+        name: "provisioned webhooks",
+        sql: "
+        -- The webhook in the config is optional now. SQLite can't drop a NOT NULL, so the table is rebuilt
+        CREATE TABLE rooms_new (
+            discord TEXT PRIMARY KEY,
+            discord_guild   TEXT NOT NULL,
+            matrix  TEXT NOT NULL UNIQUE,
+            webhook TEXT,
+            max_upload_size INTEGER
+        );
+        INSERT INTO rooms_new SELECT discord, discord_guild, matrix, NULLIF(webhook, ''), max_upload_size FROM rooms;
+        DROP TABLE rooms;
+        ALTER TABLE rooms_new RENAME TO rooms;
+
+        -- Webhooks the relay made (or found) in a channel itself, used instead of the configured one
+        CREATE TABLE webhooks (
+            channel TEXT PRIMARY KEY,
+            id  TEXT NOT NULL,
+            token   TEXT NOT NULL
+        );
+        ",
+        postgres: "
+        ALTER TABLE rooms ALTER COLUMN webhook DROP NOT NULL;
+
+        CREATE TABLE webhooks (
+            channel TEXT PRIMARY KEY,
+            id  TEXT NOT NULL,
+            token   TEXT NOT NULL
+        );
+        ",
+    },
+    Migration {
+        version: 5,
+        name: "configured rooms",
+        sql: "
+        -- Set on the rooms that came from the config, so the ones taken out of it can be unbridged on startup
+        ALTER TABLE rooms ADD COLUMN configured INTEGER NOT NULL DEFAULT 0;
+        ",
+        postgres: "
+        ALTER TABLE rooms ADD COLUMN configured BOOLEAN NOT NULL DEFAULT FALSE;
+        ",
+    },
+];
+
+/// The version a database is at once every migration has been applied
+pub fn latest_version() -> u32 {
+    return MIGRATIONS.last().map(|migration| migration.version).unwrap_or(0);
+}
+
+pub fn current_version(database: &Connection) -> RelayResult<u32> {
+    return Ok(database.query_row("PRAGMA user_version", (), |row| row.get(0))?);
+}
+
+/// Migrations that haven't been applied yet, in the order they will be
+pub fn pending(database: &Connection) -> RelayResult<Vec<&'static Migration>> {
+    return pending_after(current_version(database)?);
+}
+
+/// Migrations after a version, every migration for a database that doesn't exist yet (version 0)
+pub fn pending_after(version: u32) -> RelayResult<Vec<&'static Migration>> {
+    // Running an older relay against a newer database could silently lose whatever the newer one added
+    if version > latest_version() {
+        return Err(RelayError::Storage(format!(
+            "database is at version {}, but this relay only knows up to version {}",
+            version,
+            latest_version()
+        )));
+    }
+    return Ok(MIGRATIONS.iter().filter(|migration| migration.version > version).collect());
+}
+
+/// Applies every pending migration, each in its own transaction. With dry_run they are all applied in one
+/// transaction which is rolled back, so it checks they would apply without changing anything.
+/// Returns the migrations that were (or would have been) applied.
+pub fn migrate(database: &Connection, dry_run: bool) -> RelayResult<Vec<&'static Migration>> {
+    let pending = pending(database)?;
+
+    if dry_run {
+        let transaction = database.unchecked_transaction()?;
+        for migration in pending.iter() {
+            transaction.execute_batch(migration.sql).map_err(|err| failed(migration, err))?;
+        }
+        transaction.rollback()?;
+        return Ok(pending);
+    }
+
+    for migration in pending.iter() {
+        let transaction = database.unchecked_transaction()?;
+        transaction.execute_batch(migration.sql).map_err(|err| failed(migration, err))?;
+        transaction.pragma_update(None, "user_version", migration.version)?;
+        transaction.commit()?;
+        println!("Applied database migration {}: {}", migration.version, migration.name);
+    }
+    return Ok(pending);
+}
+
+fn failed(migration: &Migration, err: impl std::fmt::Display) -> RelayError {
+    return RelayError::Storage(format!("migration {} ({}) failed: {}", migration.version, migration.name, err));
+}
+
+// Postgres has no user_version, so the version is kept in a table of its own. It is only read here, a database
+// without the table is at version 0
+pub fn postgres_version(database: &mut postgres::Client) -> RelayResult<u32> {
+    let exists: bool = database.query_one("SELECT to_regclass('schema_version') IS NOT NULL", &[])?.get(0);
+    if !exists {
+        return Ok(0);
+    }
+    let row = database.query_opt("SELECT version FROM schema_version", &[])?;
+    return Ok(row.map(|row| row.get::<_, i32>(0) as u32).unwrap_or(0));
+}
+
+/// The same as migrate, for postgres
+pub fn migrate_postgres(database: &mut postgres::Client, dry_run: bool) -> RelayResult<Vec<&'static Migration>> {
+    let pending = pending_after(postgres_version(database)?)?;
+    if !dry_run {
+        database.batch_execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)")?;
+    }
+
+    if dry_run {
+        let mut transaction = database.transaction()?;
+        for migration in pending.iter() {
+            transaction.batch_execute(migration.postgres).map_err(|err| failed(migration, err))?;
+        }
+        transaction.rollback()?;
+        return Ok(pending);
+    }
+
+    for migration in pending.iter() {
+        let mut transaction = database.transaction()?;
+        transaction.batch_execute(migration.postgres).map_err(|err| failed(migration, err))?;
+        transaction.execute("DELETE FROM schema_version", &[])?;
+        transaction.execute("INSERT INTO schema_version (version) VALUES ($1)", &[&(migration.version as i32)])?;
+        transaction.commit()?;
+        println!("Applied database migration {}: {}", migration.version, migration.name);
+    }
+    return Ok(pending);
+}
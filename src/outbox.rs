@@ -0,0 +1,284 @@
+// Every outbound send, edit and delete goes through here, so nothing is lost when discord or the homeserver is down.
+// Deliveries are stored in the outbox table and a worker sends them, in order for each destination.
+
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::{
+    chat_service::{Attachment, FullMessage, FullReaction, Message, OutboxItem, Store, User},
+    discord,
+    error::{RelayError, RelayResult},
+    matrix,
+};
+
+// First retry is after BASE_DELAY, doubling every attempt up to MAX_DELAY
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+// Attempts before an item is dead lettered
+const MAX_ATTEMPTS: u32 = 12;
+// How often the outbox is checked when nothing wakes the worker
+const IDLE_WAIT: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref WAKE: Notify = Notify::new();
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Delivery {
+    DiscordMessage { message: FullMessage },
+    DiscordEdit { message: FullMessage },
+    DiscordDelete { message: Message },
+    DiscordReaction { reaction: FullReaction },
+    DiscordDeleteReaction { reaction: Message },
+
+    MatrixMessage { message: FullMessage },
+    MatrixAttachment { message: FullMessage, attachment: Attachment },
+    MatrixEdit { message: FullMessage },
+    // deleted_by is the moderator who deleted someone else's message, the reason is what the redaction says
+    MatrixDelete {
+        message: Message,
+        #[serde(default)]
+        deleted_by: Option<User>,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    MatrixReaction { reaction: FullReaction },
+    MatrixDeleteReaction { reaction: Message },
+}
+
+impl Delivery {
+    fn target_service(&self) -> &str {
+        match self {
+            Delivery::DiscordMessage { .. }
+            | Delivery::DiscordEdit { .. }
+            | Delivery::DiscordDelete { .. }
+            | Delivery::DiscordReaction { .. }
+            | Delivery::DiscordDeleteReaction { .. } => "discord",
+            _ => "matrix",
+        }
+    }
+
+    // The message (or reaction) on the sending side
+    fn source(&self) -> &Message {
+        match self {
+            Delivery::DiscordMessage { message }
+            | Delivery::DiscordEdit { message }
+            | Delivery::MatrixMessage { message }
+            | Delivery::MatrixAttachment { message, .. }
+            | Delivery::MatrixEdit { message } => &message.message,
+            Delivery::DiscordReaction { reaction } | Delivery::MatrixReaction { reaction } => &reaction.message,
+            Delivery::DiscordDelete { message } | Delivery::MatrixDelete { message, .. } => message,
+            Delivery::DiscordDeleteReaction { reaction } | Delivery::MatrixDeleteReaction { reaction } => reaction,
+        }
+    }
+
+    /// Everything sent from one room to the other service is delivered in the order it happened
+    pub fn destination(&self) -> String {
+        return format!("{}:{}", self.target_service(), self.source().room_id);
+    }
+
+    // Sends it, and stores what it was relayed as
+    async fn deliver(&self, store: &Store) -> RelayResult<()> {
+        match self {
+            // The parts are stored as they are sent, a retry only sends the rest
+            Delivery::DiscordMessage { message } => {
+                discord::relay::relay_message(store, message.clone()).await?;
+            }
+            Delivery::DiscordEdit { message } => discord::relay::edit_message(store, message.clone()).await?,
+            Delivery::DiscordDelete { message } => {
+                discord::relay::delete_message(store, message.clone()).await?;
+                store.delete_message(message.clone()).await?;
+            }
+            Delivery::DiscordReaction { reaction } => {
+                let relayed = discord::relay::relay_reaction(store, reaction.clone()).await?;
+                if relayed.is_some() {
+                    store.create_reaction(reaction.reaction.clone(), relayed.unwrap()).await?;
+                }
+            }
+            Delivery::DiscordDeleteReaction { reaction } => {
+                discord::relay::delete_reaction(store, reaction.clone()).await?;
+                store.delete_reaction(reaction.clone()).await?;
+            }
+
+            // A retry after storing failed gets the same event back, rather than sending it twice
+            Delivery::MatrixMessage { message } => {
+                let txn_id = matrix::relay::transaction_id(&message.message, "text");
+                let relayed = matrix::relay::relay_message(store, message.clone(), Some(txn_id)).await?;
+                store.create_message(message.message.clone(), relayed).await?;
+            }
+            Delivery::MatrixAttachment { message, attachment } => {
+                let txn_id = matrix::relay::transaction_id(&message.message, &attachment.url);
+                let relayed = matrix::relay::relay_attachment(store, message.clone(), attachment.clone(), Some(txn_id)).await?;
+                store.create_message(message.message.clone(), relayed).await?;
+            }
+            Delivery::MatrixEdit { message } => matrix::relay::edit_message(store, message.clone()).await?,
+            Delivery::MatrixDelete { message, deleted_by, reason } => {
+                matrix::relay::delete_message(store, message.clone(), deleted_by.as_ref(), reason.as_deref()).await?;
+                store.delete_message(message.clone()).await?;
+            }
+            Delivery::MatrixReaction { reaction } => {
+                let relayed = matrix::relay::relay_reaction(store, reaction.clone()).await?;
+                if relayed.is_some() {
+                    store.create_reaction(reaction.reaction.clone(), relayed.unwrap()).await?;
+                }
+            }
+            Delivery::MatrixDeleteReaction { reaction } => {
+                matrix::relay::delete_reaction(store, reaction.clone()).await?;
+                store.delete_reaction(reaction.clone()).await?;
+            }
+        }
+        return Ok(());
+    }
+
+    // Only messages get a notice, a missing reaction or deletion isn't worth interrupting anyone for
+    async fn notify_failure(&self, err: &RelayError) {
+        match self {
+            Delivery::DiscordMessage { message } | Delivery::DiscordEdit { message } => {
+                matrix::bot::notify_failure(&message.message, err).await;
+            }
+            Delivery::MatrixMessage { message } | Delivery::MatrixAttachment { message, .. } | Delivery::MatrixEdit { message } => {
+                discord::bot::notify_failure(&message.message, err).await;
+            }
+            _ => {}
+        }
+    }
+}
+
+pub fn now_millis() -> i64 {
+    return SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis() as i64).unwrap_or(0);
+}
+
+/// How long to wait before trying again after a failed attempt, None if the item should be dead lettered
+pub fn retry_delay(attempts: u32, err: &RelayError) -> Option<Duration> {
+    if !err.is_temporary() || attempts >= MAX_ATTEMPTS {
+        return None;
+    }
+    if let RelayError::RateLimited(Some(retry_after)) = err {
+        return Some(*retry_after);
+    }
+    let delay = BASE_DELAY.saturating_mul(1 << attempts.saturating_sub(1).min(16));
+    return Some(delay.min(MAX_DELAY));
+}
+
+/// Queues the delivery and wakes the worker
+pub async fn enqueue(store: &Store, delivery: Delivery) -> RelayResult<()> {
+    let payload = serde_json::to_string(&delivery)?;
+    store.enqueue_outbox(&delivery.destination(), &payload, now_millis()).await?;
+    WAKE.notify_one();
+    return Ok(());
+}
+
+/// Same as enqueue, but failures are only logged, for event handlers which have nowhere to return them
+pub async fn enqueue_or_log(store: &Store, delivery: Delivery) {
+    let destination = delivery.destination();
+    let res = enqueue(store, delivery).await;
+    if res.is_err() {
+        println!("Failed to queue delivery to {}: {}", destination, res.err().unwrap());
+    }
+}
+
+async fn process(store: &Store, item: OutboxItem) {
+    let delivery = serde_json::from_str::<Delivery>(&item.payload);
+    if delivery.is_err() {
+        let err = delivery.err().unwrap();
+        println!("Dead lettering unreadable outbox item {}: {}", item.id, err);
+        store.outbox_dead(item.id, item.attempts, &err.to_string()).await.ok();
+        return;
+    }
+    let delivery = delivery.unwrap();
+
+    let res = delivery.deliver(store).await;
+    if res.is_ok() {
+        let done = store.outbox_done(item.id).await;
+        if done.is_err() {
+            println!("Delivered outbox item {} but couldn't remove it: {}", item.id, done.err().unwrap());
+        }
+        return;
+    }
+
+    let err = res.err().unwrap();
+    let attempts = item.attempts + 1;
+    let delay = retry_delay(attempts, &err);
+    if delay.is_some() {
+        let delay = delay.unwrap();
+        println!("Delivery {} to {} failed (attempt {}), retrying in {:?}: {}", item.id, item.destination, attempts, delay, err);
+        store.outbox_retry(item.id, attempts, now_millis() + delay.as_millis() as i64, &err.to_string()).await.ok();
+        return;
+    }
+
+    println!("Delivery {} to {} failed for good after {} attempts: {}", item.id, item.destination, attempts, err);
+    store.outbox_dead(item.id, attempts, &err.to_string()).await.ok();
+    delivery.notify_failure(&err).await;
+}
+
+/// Puts dead letters back in the queue, all of them when no ids are given. Returns the ids that were requeued
+pub async fn requeue_dead_letters(store: &Store, ids: Option<Vec<i64>>) -> RelayResult<Vec<i64>> {
+    let dead: Vec<i64> = store.dead_letters().await?.into_iter().map(|item| item.id).collect();
+    let ids = ids.unwrap_or(dead.clone());
+    let missing = ids.iter().find(|id| !dead.contains(id));
+    if missing.is_some() {
+        return Err(RelayError::NotFound(format!("dead letter {}", missing.unwrap())));
+    }
+    for id in ids.iter() {
+        store.requeue_dead_letter(*id, now_millis()).await?;
+    }
+    WAKE.notify_one();
+    return Ok(ids);
+}
+
+/// The heads that can be delivered now, skipping destinations that are still busy with their previous item, and how
+/// long until the next of the others is due
+pub fn due_heads(heads: Vec<OutboxItem>, busy: &HashSet<String>, now: i64) -> (Vec<OutboxItem>, Duration) {
+    let (due, waiting): (Vec<OutboxItem>, Vec<OutboxItem>) = heads
+        .into_iter()
+        .filter(|item| !busy.contains(&item.destination))
+        .partition(|item| item.next_attempt <= now);
+
+    let next = waiting.iter().map(|item| item.next_attempt).min();
+    let wait = next.map(|next| Duration::from_millis((next - now).max(0) as u64)).unwrap_or(IDLE_WAIT).min(IDLE_WAIT);
+    return (due, wait);
+}
+
+/// Runs forever, delivering whatever is queued
+pub async fn run(store: Store) {
+    let dead = store.dead_letters().await.map(|items| items.len()).unwrap_or(0);
+    if dead > 0 {
+        println!("{} dead letters in the outbox, list them with the dead-letters command", dead);
+    }
+
+    // Destinations don't wait on each other, a slow delivery only holds back the items after it
+    let store = &store;
+    let mut in_flight = FuturesUnordered::new();
+    let mut busy: HashSet<String> = HashSet::new();
+    loop {
+        let mut wait = BASE_DELAY;
+        let heads = store.outbox_heads().await;
+        if heads.is_ok() {
+            let (due, next) = due_heads(heads.unwrap(), &busy, now_millis());
+            wait = next;
+            for item in due {
+                let destination = item.destination.clone();
+                busy.insert(destination.clone());
+                in_flight.push(async move {
+                    process(store, item).await;
+                    return destination;
+                });
+            }
+        } else {
+            println!("Failed to read the outbox: {}", heads.err().unwrap());
+        }
+
+        // A finished delivery lets the next item of its destination go
+        tokio::select! {
+            done = in_flight.next(), if !in_flight.is_empty() => {
+                busy.remove(&done.unwrap());
+            }
+            _ = tokio::time::timeout(wait, WAKE.notified()) => {}
+        }
+    }
+}
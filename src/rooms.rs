@@ -0,0 +1,156 @@
+// Which discord channel is bridged to which matrix room. The database has the list, a copy of it is kept here so the
+// many lookups (some of them outside of async code) don't have to go to the database. Rooms in the config are written
+// to the database on every start, so the config always wins over a command, and the ones taken out of the config
+// stop being bridged.
+
+use std::sync::RwLock;
+
+use crate::chat_service::Store;
+use crate::error::RelayResult;
+use crate::{discord, matrix, Entry};
+
+lazy_static! {
+    static ref ROOMS: RwLock<Vec<Entry>> = RwLock::new(Vec::new());
+}
+
+/// Seeds the rooms of the config, then loads every bridged room
+pub async fn load(store: &Store, configured: &[Entry]) -> RelayResult<()> {
+    // Rooms that were taken out of the config while the relay wasn't running
+    for room in store.rooms().await?.iter().filter(|room| room.configured) {
+        if !configured.iter().any(|entry| entry.discord == room.discord && entry.matrix == room.matrix) {
+            store.delete_room(&room.matrix).await?;
+            println!("Stopped bridging {} -> {}, it isn't in the config anymore", room.discord, room.matrix);
+        }
+    }
+    for room in configured.iter() {
+        store.create_room(&from_config(room)).await?;
+    }
+    return reload(store).await;
+}
+
+/// A room of the config, as it is stored
+pub fn from_config(room: &Entry) -> Entry {
+    let mut room = room.clone();
+    room.configured = true;
+    return room;
+}
+
+async fn reload(store: &Store) -> RelayResult<()> {
+    let rooms = store.rooms().await?;
+    *ROOMS.write().unwrap() = rooms;
+    return Ok(());
+}
+
+pub fn all() -> Vec<Entry> {
+    return ROOMS.read().unwrap().clone();
+}
+
+pub fn by_discord(channel_id: &str) -> Option<Entry> {
+    return ROOMS.read().unwrap().iter().find(|room| room.discord == channel_id).cloned();
+}
+
+pub fn by_matrix(room_id: &str) -> Option<Entry> {
+    return ROOMS.read().unwrap().iter().find(|room| room.matrix == room_id).cloned();
+}
+
+/// Bridges a room from now on, replacing whatever either side was bridged to
+pub async fn link(store: &Store, room: Entry) -> RelayResult<()> {
+    store.create_room(&room).await?;
+    return reload(store).await;
+}
+
+/// Stops bridging a matrix room, returns what it was bridged to
+pub async fn unlink(store: &Store, matrix: &str) -> RelayResult<Option<Entry>> {
+    let room = by_matrix(matrix);
+    if room.is_none() {
+        return Ok(None);
+    }
+    store.delete_room(matrix).await?;
+    reload(store).await?;
+    return Ok(room);
+}
+
+/// Bridges a discord channel and a matrix room. The relay's webhook is set up straight away, so a missing permission
+/// shows up in the answer to the command rather than on the first message
+pub async fn bridge(store: &Store, discord_channel: &str, matrix_room: &str) -> RelayResult<Entry> {
+    matrix::relay::join_room(matrix_room).await?;
+    let guild = discord::relay::channel_guild(discord_channel).await?;
+    discord::relay::provision_webhook(store, discord_channel).await?;
+    let room = Entry {
+        discord: discord_channel.to_owned(),
+        discord_guild: guild,
+        matrix: matrix_room.to_owned(),
+        webhook: None,
+        max_upload_size: None,
+        configured: false,
+    };
+    link(store, room.clone()).await?;
+    return Ok(room);
+}
+
+/// Why a channel and a room can't be bridged, when either is bridged already. Bridging never takes over a bridge, it
+/// has to be unbridged first
+pub fn already_bridged(rooms: &[Entry], discord_channel: &str, matrix_room: &str) -> Option<String> {
+    let existing = rooms.iter().find(|room| room.discord == discord_channel || room.matrix == matrix_room);
+    if existing.is_none() {
+        return None;
+    }
+    let existing = existing.unwrap();
+    return Some(format!(
+        "Discord channel {} is already bridged with Matrix room {}, it has to be unbridged first",
+        existing.discord, existing.matrix
+    ));
+}
+
+/// Runs the bridge command of the matrix side, returns what to answer with
+pub async fn bridge_command(store: &Store, discord_channel: &str, matrix_room: &str) -> String {
+    let bridged = already_bridged(&all(), discord_channel, matrix_room);
+    if bridged.is_some() {
+        return bridged.unwrap();
+    }
+    let res = bridge(store, discord_channel, matrix_room).await;
+    match res {
+        Ok(room) => return format!("Bridged Discord channel {} with Matrix room {}", room.discord, room.matrix),
+        Err(err) => return format!("Couldn't bridge {} with {}: {}", discord_channel, matrix_room, err),
+    }
+}
+
+/// Runs the bridge command of the discord side. Being a discord admin doesn't say anything about the matrix room, so
+/// this only asks in the room, and a bridge admin there approves it with the matrix command
+pub async fn request_bridge(discord_channel: &str, matrix_room: &str, requested_by: &str) -> String {
+    let bridged = already_bridged(&all(), discord_channel, matrix_room);
+    if bridged.is_some() {
+        return bridged.unwrap();
+    }
+    let guild = discord::relay::channel_guild(discord_channel).await;
+    if guild.is_err() {
+        return format!("Couldn't bridge {} with {}: {}", discord_channel, matrix_room, guild.err().unwrap());
+    }
+
+    let name = discord::bot::channel_name(discord_channel).await;
+    let notice = format!(
+        "{} asked to bridge this room with the Discord channel {} ({}). A bridge admin can approve it by sending !bridge {} here",
+        requested_by, name, discord_channel, discord_channel
+    );
+    let res = matrix::relay::send_notice(matrix_room, &notice).await;
+    if res.is_err() {
+        return format!("Couldn't ask in {}: {}", matrix_room, res.err().unwrap());
+    }
+    return format!("Asked in {}, a Matrix bridge admin has to approve it by sending !bridge {} there", matrix_room, discord_channel);
+}
+
+/// Runs the unbridge command of either side, returns what to answer with
+pub async fn unbridge_command(store: &Store, matrix_room: &str) -> String {
+    let res = unlink(store, matrix_room).await;
+    match res {
+        Ok(Some(room)) => {
+            let mut reply = format!("Stopped bridging Discord channel {} with Matrix room {}", room.discord, room.matrix);
+            if room.configured {
+                reply = format!("{}. It's in the config, so it will be bridged again on restart", reply);
+            }
+            return reply;
+        }
+        Ok(None) => return "This room isn't bridged".to_owned(),
+        Err(err) => return format!("Couldn't unbridge: {}", err),
+    }
+}
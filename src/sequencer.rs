@@ -0,0 +1,112 @@
+// Event handlers on both sides run concurrently, so a message that takes longer to prepare (a reply to quote, a thread to look up)
+// could be queued after an edit or a second message that arrived later. Handlers take a ticket as soon as an event arrives, and
+// wait for their turn before queuing anything. Rooms are independent, a slow room never holds up another one.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+lazy_static! {
+    static ref SEQUENCER: Sequencer = Sequencer::new();
+}
+
+struct RoomQueue {
+    // Number given to the next ticket
+    issued: u64,
+    // Ticket whose turn it is
+    serving: u64,
+    // Tickets dropped before their turn came, skipped when it does
+    finished: BTreeSet<u64>,
+    notify: Arc<Notify>,
+}
+
+#[derive(Clone)]
+pub struct Sequencer {
+    rooms: Arc<Mutex<HashMap<String, RoomQueue>>>,
+}
+
+/// A place in a room's queue. The turn is held until the ticket is dropped
+pub struct Ticket {
+    sequencer: Sequencer,
+    room: String,
+    number: u64,
+    notify: Arc<Notify>,
+}
+
+impl Sequencer {
+    pub fn new() -> Sequencer {
+        return Sequencer { rooms: Arc::new(Mutex::new(HashMap::new())) };
+    }
+
+    /// Takes the next place in the room's queue. Call this before the first await of a handler, that's what sets its order
+    pub fn ticket(&self, room: &str) -> Ticket {
+        let mut rooms = self.rooms.lock().unwrap();
+        let queue = rooms.entry(room.to_owned()).or_insert_with(|| RoomQueue {
+            issued: 0,
+            serving: 0,
+            finished: BTreeSet::new(),
+            notify: Arc::new(Notify::new()),
+        });
+        let number = queue.issued;
+        queue.issued += 1;
+        return Ticket {
+            sequencer: self.clone(),
+            room: room.to_owned(),
+            number: number,
+            notify: queue.notify.clone(),
+        };
+    }
+
+    fn is_serving(&self, room: &str, number: u64) -> bool {
+        let rooms = self.rooms.lock().unwrap();
+        return rooms.get(room).map(|queue| queue.serving == number).unwrap_or(false);
+    }
+
+    fn finish(&self, room: &str, number: u64) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let queue = rooms.get_mut(room);
+        if queue.is_none() {
+            return;
+        }
+        let queue = queue.unwrap();
+
+        queue.finished.insert(number);
+        while queue.finished.remove(&queue.serving) {
+            queue.serving += 1;
+        }
+        queue.notify.notify_waiters();
+
+        // Nothing is waiting, so the room doesn't need to be remembered
+        if queue.serving == queue.issued {
+            rooms.remove(room);
+        }
+    }
+}
+
+impl Ticket {
+    /// Waits until every earlier ticket of the room has been dropped
+    pub async fn turn(&self) {
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            // Registers for the wake up before checking, so one that happens in between isn't missed
+            notified.as_mut().enable();
+            if self.sequencer.is_serving(&self.room, self.number) {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.sequencer.finish(&self.room, self.number);
+    }
+}
+
+/// Takes a ticket for a room of a service, from the sequencer shared by all handlers
+pub fn ticket(service: &str, room_id: &str) -> Ticket {
+    return SEQUENCER.ticket(&format!("{}:{}", service, room_id));
+}
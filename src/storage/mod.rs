@@ -0,0 +1,46 @@
+// The implementations of chat_service::Storage. Which one is used comes from database_url in the config
+
+use std::sync::Arc;
+
+use crate::chat_service::Store;
+use crate::error::{RelayError, RelayResult};
+use crate::migrations::Migration;
+
+pub mod postgresql;
+pub mod sqlite;
+
+fn is_postgres(url: &str) -> bool {
+    return url.starts_with("postgres://") || url.starts_with("postgresql://");
+}
+
+// sqlite:// is optional, a plain path works too
+fn sqlite_path(url: &str) -> &str {
+    return url.strip_prefix("sqlite://").unwrap_or(url);
+}
+
+/// Opens the database a url points at and brings its schema up to date. postgres:// (or postgresql://) urls
+/// are postgres, anything else is a sqlite file
+pub async fn open(url: &str) -> RelayResult<Store> {
+    if is_postgres(url) {
+        return Ok(Arc::new(postgresql::PostgresStore::open(url).await?));
+    }
+    return Ok(Arc::new(sqlite::SqliteStore::open(sqlite_path(url)).await?));
+}
+
+/// The migrations opening the database would apply, checked without changing anything
+pub async fn pending_migrations(url: &str) -> RelayResult<Vec<&'static Migration>> {
+    if is_postgres(url) {
+        return postgresql::PostgresStore::pending_migrations(url).await;
+    }
+    return sqlite::SqliteStore::pending_migrations(sqlite_path(url)).await;
+}
+
+// Runs database work on tokio's blocking threads, so a slow query doesn't hold up the event handlers
+async fn blocking<T, F>(work: F) -> RelayResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> RelayResult<T> + Send + 'static,
+{
+    let res = tokio::task::spawn_blocking(work).await;
+    return res.unwrap_or_else(|err| Err(RelayError::Storage(format!("database task failed: {}", err))));
+}
@@ -0,0 +1,529 @@
+// Storage in a postgres database, for deployments that want it shared and backed up with everything else
+
+use std::path::Path;
+
+use async_trait::async_trait;
+use postgres::types::ToSql;
+use native_tls::TlsConnector;
+use postgres::Client;
+use postgres_native_tls::MakeTlsConnector;
+use r2d2::Pool;
+use r2d2_postgres::PostgresConnectionManager;
+use rusqlite::{Connection, OpenFlags};
+
+use crate::chat_service::{Message, OutboxItem, Storage};
+use crate::error::{RelayError, RelayResult};
+use crate::migrations::{self, Migration};
+use crate::Entry;
+
+use super::blocking;
+
+// Postgres handles many writers at once, unlike sqlite, but every bridge sharing the server has its own pool
+const POOL_SIZE: u32 = 8;
+
+#[derive(Clone)]
+pub struct PostgresStore {
+    pool: Pool<PostgresConnectionManager<MakeTlsConnector>>,
+}
+
+// Whether TLS is used is up to the url's sslmode: disable, prefer (the default, falls back to plain connections) or
+// require. Certificates are checked against the system's roots
+fn tls() -> RelayResult<MakeTlsConnector> {
+    let connector = TlsConnector::new().map_err(|err| RelayError::Storage(format!("couldn't set up TLS: {}", err)))?;
+    return Ok(MakeTlsConnector::new(connector));
+}
+
+impl PostgresStore {
+    /// Connects to the database at url and brings its schema up to date
+    pub async fn open(url: &str) -> RelayResult<PostgresStore>
+    {
+        let config = url.parse::<postgres::Config>()?;
+        // Building the pool connects straight away, which blocks
+        let pool = blocking(move || {
+            let manager = PostgresConnectionManager::new(config, tls()?);
+            return Ok(Pool::builder().max_size(POOL_SIZE).build(manager)?);
+        }).await?;
+
+        let store = PostgresStore { pool: pool };
+        store.run(|database| migrations::migrate_postgres(database, false)).await?;
+        return Ok(store);
+    }
+
+    pub async fn pending_migrations(url: &str) -> RelayResult<Vec<&'static Migration>>
+    {
+        let url = url.to_owned();
+        return blocking(move || {
+            let mut database = Client::connect(&url, tls()?)?;
+            return migrations::migrate_postgres(&mut database, true);
+        }).await;
+    }
+
+    async fn run<T, F>(&self, work: F) -> RelayResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Client) -> RelayResult<T> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        return blocking(move || {
+            let mut database = pool.get()?;
+            return work(&mut database);
+        }).await;
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStore {
+    async fn create_message(&self, source: Message, relayed: Message) -> RelayResult<()>
+    {
+        return self.run(move |database| {
+            database.execute("
+            INSERT INTO messages (service_org, server_id_org, room_id_org, id_org, service_out, server_id_out, room_id_out, id_out, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, EXTRACT(EPOCH FROM now())::BIGINT)
+            ON CONFLICT DO NOTHING",
+            &[&source.service, &source.server_id, &source.room_id, &source.id, &relayed.service, &relayed.server_id, &relayed.room_id, &relayed.id])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn message_origin(&self, relayed: Message) -> RelayResult<Option<Message>>
+    {
+        return self.run(move |database| message_origin(database, &relayed)).await;
+    }
+
+    async fn message_relays(&self, source: Message) -> RelayResult<Vec<Message>>
+    {
+        return self.run(move |database| {
+            return query_messages(database, "SELECT service_out, server_id_out, room_id_out, id_out FROM messages WHERE service_org=$1 AND server_id_org=$2 AND room_id_org=$3 AND id_org=$4 ORDER BY id", &source);
+        }).await;
+    }
+
+    async fn delete_message(&self, msg: Message) -> RelayResult<()>
+    {
+        return self.run(move |database| {
+            let origin = message_origin(database, &msg)?.unwrap_or(msg);
+            database.execute("DELETE FROM messages WHERE service_org=$1 AND room_id_org=$2 AND id_org=$3", &[&origin.service, &origin.room_id, &origin.id])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn delete_relayed_message(&self, relayed: Message) -> RelayResult<()>
+    {
+        return self.run(move |database| {
+            database.execute("DELETE FROM messages WHERE service_out=$1 AND room_id_out=$2 AND id_out=$3", &[&relayed.service, &relayed.room_id, &relayed.id])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn create_reaction(&self, source: Message, relayed: Message) -> RelayResult<()>
+    {
+        return self.run(move |database| {
+            database.execute("
+            INSERT INTO reactions (service_org, server_id_org, room_id_org, id_org, service_out, server_id_out, room_id_out, id_out)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            &[&source.service, &source.server_id, &source.room_id, &source.id, &relayed.service, &relayed.server_id, &relayed.room_id, &relayed.id])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn reaction_relays(&self, source: Message) -> RelayResult<Vec<Message>>
+    {
+        return self.run(move |database| {
+            return query_messages(database, "SELECT service_out, server_id_out, room_id_out, id_out FROM reactions WHERE service_org=$1 AND server_id_org=$2 AND room_id_org=$3 AND id_org=$4", &source);
+        }).await;
+    }
+
+    async fn reaction_origins(&self, relayed: Message) -> RelayResult<Vec<Message>>
+    {
+        return self.run(move |database| {
+            return query_messages(database, "SELECT service_org, server_id_org, room_id_org, id_org FROM reactions WHERE service_out=$1 AND server_id_out=$2 AND room_id_out=$3 AND id_out=$4", &relayed);
+        }).await;
+    }
+
+    async fn delete_reaction(&self, reaction: Message) -> RelayResult<()>
+    {
+        return self.run(move |database| {
+            database.execute("DELETE FROM reactions WHERE (service_org=$1 AND room_id_org=$2 AND id_org=$3) OR (service_out=$1 AND room_id_out=$2 AND id_out=$3)",
+            &[&reaction.service, &reaction.room_id, &reaction.id])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn create_thread(&self, thread: Message, root: Message) -> RelayResult<()>
+    {
+        return self.run(move |database| {
+            database.execute("
+            INSERT INTO threads (service_org, server_id_org, room_id_org, id_org, service_out, server_id_out, room_id_out, id_out)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT DO NOTHING",
+            &[&thread.service, &thread.server_id, &thread.room_id, &thread.id, &root.service, &root.server_id, &root.room_id, &root.id])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn thread_root(&self, thread: Message) -> RelayResult<Option<Message>>
+    {
+        return self.run(move |database| {
+            return Ok(query_messages(database, "SELECT service_out, server_id_out, room_id_out, id_out FROM threads WHERE service_org=$1 AND server_id_org=$2 AND room_id_org=$3 AND id_org=$4", &thread)?.pop());
+        }).await;
+    }
+
+    async fn root_thread(&self, root: Message) -> RelayResult<Option<Message>>
+    {
+        return self.run(move |database| {
+            return Ok(query_messages(database, "SELECT service_org, server_id_org, room_id_org, id_org FROM threads WHERE service_out=$1 AND server_id_out=$2 AND room_id_out=$3 AND id_out=$4", &root)?.pop());
+        }).await;
+    }
+
+    async fn avatar_mxc(&self, hash: &str) -> RelayResult<Option<String>>
+    {
+        let hash = hash.to_owned();
+        return self.run(move |database| {
+            let row = database.query_opt("SELECT mxc FROM avatars WHERE hash=$1", &[&hash])?;
+            return Ok(row.map(|row| row.get(0)));
+        }).await;
+    }
+
+    async fn create_avatar(&self, hash: &str, mxc: &str) -> RelayResult<()>
+    {
+        let (hash, mxc) = (hash.to_owned(), mxc.to_owned());
+        return self.run(move |database| {
+            database.execute("INSERT INTO avatars (hash, mxc) VALUES ($1, $2) ON CONFLICT (hash) DO UPDATE SET mxc=$2", &[&hash, &mxc])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn puppet_avatar(&self, user_id: &str) -> RelayResult<Option<String>>
+    {
+        let user_id = user_id.to_owned();
+        return self.run(move |database| {
+            let row = database.query_opt("SELECT avatar_hash FROM puppets WHERE user_id=$1", &[&user_id])?;
+            return Ok(row.and_then(|row| row.get(0)));
+        }).await;
+    }
+
+    async fn set_puppet_avatar(&self, user_id: &str, hash: &str) -> RelayResult<()>
+    {
+        let (user_id, hash) = (user_id.to_owned(), hash.to_owned());
+        return self.run(move |database| {
+            database.execute("
+            INSERT INTO puppets (user_id, avatar_hash) VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET avatar_hash=$2", &[&user_id, &hash])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn puppet_name(&self, user_id: &str, room_id: &str) -> RelayResult<Option<String>>
+    {
+        let (user_id, room_id) = (user_id.to_owned(), room_id.to_owned());
+        return self.run(move |database| {
+            let row = database.query_opt("SELECT displayname FROM puppet_names WHERE user_id=$1 AND room_id=$2", &[&user_id, &room_id])?;
+            return Ok(row.map(|row| row.get(0)));
+        }).await;
+    }
+
+    async fn set_puppet_name(&self, user_id: &str, room_id: &str, displayname: &str) -> RelayResult<()>
+    {
+        let (user_id, room_id, displayname) = (user_id.to_owned(), room_id.to_owned(), displayname.to_owned());
+        return self.run(move |database| {
+            database.execute("
+            INSERT INTO puppet_names (user_id, room_id, displayname) VALUES ($1, $2, $3)
+            ON CONFLICT (user_id, room_id) DO UPDATE SET displayname=$3", &[&user_id, &room_id, &displayname])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn clear_puppet_room_names(&self, user_id: &str) -> RelayResult<()>
+    {
+        let user_id = user_id.to_owned();
+        return self.run(move |database| {
+            database.execute("DELETE FROM puppet_names WHERE user_id=$1 AND room_id!=''", &[&user_id])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn rooms(&self) -> RelayResult<Vec<Entry>>
+    {
+        return self.run(move |database| {
+            let rows = database.query("SELECT discord, discord_guild, matrix, webhook, max_upload_size, configured FROM rooms ORDER BY discord", &[])?;
+            return Ok(rows.iter().map(|row| Entry {
+                discord: row.get(0),
+                discord_guild: row.get(1),
+                matrix: row.get(2),
+                webhook: row.get(3),
+                max_upload_size: row.get::<_, Option<i64>>(4).map(|size| size as u64),
+                configured: row.get(5),
+            }).collect());
+        }).await;
+    }
+
+    async fn create_room(&self, room: &Entry) -> RelayResult<()>
+    {
+        let room = room.clone();
+        return self.run(move |database| {
+            // ON CONFLICT only covers one constraint, and either column can clash
+            let mut transaction = database.transaction()?;
+            transaction.execute("DELETE FROM rooms WHERE discord=$1 OR matrix=$2", &[&room.discord, &room.matrix])?;
+            transaction.execute(
+                "INSERT INTO rooms (discord, discord_guild, matrix, webhook, max_upload_size, configured) VALUES ($1, $2, $3, $4, $5, $6)",
+                &[&room.discord, &room.discord_guild, &room.matrix, &room.webhook, &room.max_upload_size.map(|size| size as i64), &room.configured],
+            )?;
+            transaction.commit()?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn delete_room(&self, matrix: &str) -> RelayResult<()>
+    {
+        let matrix = matrix.to_owned();
+        return self.run(move |database| {
+            database.execute("DELETE FROM rooms WHERE matrix=$1", &[&matrix])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn channel_webhook(&self, channel: &str) -> RelayResult<Option<(String, String)>>
+    {
+        let channel = channel.to_owned();
+        return self.run(move |database| {
+            let row = database.query_opt("SELECT id, token FROM webhooks WHERE channel=$1", &[&channel])?;
+            return Ok(row.map(|row| (row.get(0), row.get(1))));
+        }).await;
+    }
+
+    async fn set_channel_webhook(&self, channel: &str, id: &str, token: &str) -> RelayResult<()>
+    {
+        let (channel, id, token) = (channel.to_owned(), id.to_owned(), token.to_owned());
+        return self.run(move |database| {
+            database.execute("
+            INSERT INTO webhooks (channel, id, token) VALUES ($1, $2, $3)
+            ON CONFLICT (channel) DO UPDATE SET id=$2, token=$3",
+            &[&channel, &id, &token])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn enqueue_outbox(&self, destination: &str, payload: &str, now: i64) -> RelayResult<i64>
+    {
+        let (destination, payload) = (destination.to_owned(), payload.to_owned());
+        return self.run(move |database| {
+            let row = database.query_one("
+            INSERT INTO outbox (destination, payload, attempts, next_attempt, dead)
+            VALUES ($1, $2, 0, $3, FALSE) RETURNING id", &[&destination, &payload, &now])?;
+            return Ok(row.get(0));
+        }).await;
+    }
+
+    async fn outbox_heads(&self) -> RelayResult<Vec<OutboxItem>>
+    {
+        return self.run(|database| {
+            return query_outbox(database, "
+            SELECT id, destination, payload, attempts, next_attempt, last_error, dead FROM outbox
+            WHERE id IN (SELECT MIN(id) FROM outbox WHERE NOT dead GROUP BY destination)
+            ORDER BY id");
+        }).await;
+    }
+
+    async fn outbox_done(&self, id: i64) -> RelayResult<()>
+    {
+        return self.run(move |database| {
+            database.execute("DELETE FROM outbox WHERE id=$1", &[&id])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn outbox_retry(&self, id: i64, attempts: u32, next_attempt: i64, error: &str) -> RelayResult<()>
+    {
+        let error = error.to_owned();
+        return self.run(move |database| {
+            database.execute("UPDATE outbox SET attempts=$1, next_attempt=$2, last_error=$3 WHERE id=$4",
+            &[&(attempts as i64), &next_attempt, &error, &id])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn outbox_dead(&self, id: i64, attempts: u32, error: &str) -> RelayResult<()>
+    {
+        let error = error.to_owned();
+        return self.run(move |database| {
+            database.execute("UPDATE outbox SET attempts=$1, last_error=$2, dead=TRUE WHERE id=$3", &[&(attempts as i64), &error, &id])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn dead_letters(&self) -> RelayResult<Vec<OutboxItem>>
+    {
+        return self.run(|database| {
+            return query_outbox(database, "SELECT id, destination, payload, attempts, next_attempt, last_error, dead FROM outbox WHERE dead ORDER BY id");
+        }).await;
+    }
+
+    async fn requeue_dead_letter(&self, id: i64, now: i64) -> RelayResult<()>
+    {
+        return self.run(move |database| {
+            database.execute("UPDATE outbox SET dead=FALSE, attempts=0, next_attempt=$1 WHERE id=$2 AND dead", &[&now, &id])?;
+            return Ok(());
+        }).await;
+    }
+}
+
+// Runs a query which selects (service, server_id, room_id, id) columns, with the message bound to $1 to $4 in the same order
+fn query_messages(database: &mut Client, sql: &str, msg: &Message) -> RelayResult<Vec<Message>>
+{
+    let rows = database.query(sql, &[&msg.service, &msg.server_id, &msg.room_id, &msg.id])?;
+    return Ok(rows.iter().map(|row| Message {
+        service: row.get(0),
+        server_id: row.get(1),
+        room_id: row.get(2),
+        id: row.get(3),
+    }).collect());
+}
+
+fn query_outbox(database: &mut Client, sql: &str) -> RelayResult<Vec<OutboxItem>>
+{
+    let rows = database.query(sql, &[])?;
+    return Ok(rows.iter().map(|row| OutboxItem {
+        id: row.get(0),
+        destination: row.get(1),
+        payload: row.get(2),
+        attempts: row.get::<_, i64>(3) as u32,
+        next_attempt: row.get(4),
+        last_error: row.get(5),
+        dead: row.get(6),
+    }).collect());
+}
+
+fn message_origin(database: &mut Client, relayed: &Message) -> RelayResult<Option<Message>>
+{
+    let out = query_messages(database, "SELECT service_org, server_id_org, room_id_org, id_org FROM messages WHERE service_out=$1 AND server_id_out=$2 AND room_id_out=$3 AND id_out=$4", relayed)?;
+    return Ok(out.into_iter().next());
+}
+
+enum Column {
+    Text,
+    OptionalText,
+    Integer,
+    OptionalInteger,
+    // A BIGSERIAL id, its sequence is moved past the copied ids
+    Serial,
+    // Stored as 0 or 1 in sqlite
+    Boolean,
+}
+
+// Every table and column, in the order they are copied
+const TABLES: &[(&str, &[(&str, Column)])] = &[
+    ("messages", &[
+        ("id", Column::Serial),
+        ("service_org", Column::Text), ("server_id_org", Column::Text), ("room_id_org", Column::Text), ("id_org", Column::Text),
+        ("service_out", Column::Text), ("server_id_out", Column::Text), ("room_id_out", Column::Text), ("id_out", Column::Text),
+        ("created_at", Column::OptionalInteger),
+    ]),
+    ("reactions", &[
+        ("id", Column::Serial),
+        ("service_org", Column::Text), ("server_id_org", Column::Text), ("room_id_org", Column::Text), ("id_org", Column::Text),
+        ("service_out", Column::Text), ("server_id_out", Column::Text), ("room_id_out", Column::Text), ("id_out", Column::Text),
+    ]),
+    ("threads", &[
+        ("id", Column::Serial),
+        ("service_org", Column::Text), ("server_id_org", Column::Text), ("room_id_org", Column::Text), ("id_org", Column::Text),
+        ("service_out", Column::Text), ("server_id_out", Column::Text), ("room_id_out", Column::Text), ("id_out", Column::Text),
+    ]),
+    ("avatars", &[("hash", Column::Text), ("mxc", Column::Text)]),
+    ("puppets", &[("user_id", Column::Text), ("avatar_hash", Column::OptionalText)]),
+    ("puppet_names", &[("user_id", Column::Text), ("room_id", Column::Text), ("displayname", Column::Text)]),
+    ("rooms", &[
+        ("discord", Column::Text), ("discord_guild", Column::Text), ("matrix", Column::Text), ("webhook", Column::OptionalText),
+        ("max_upload_size", Column::OptionalInteger), ("configured", Column::Boolean),
+    ]),
+    ("webhooks", &[("channel", Column::Text), ("id", Column::Text), ("token", Column::Text)]),
+    ("outbox", &[
+        ("id", Column::Serial), ("destination", Column::Text), ("payload", Column::Text), ("attempts", Column::Integer),
+        ("next_attempt", Column::Integer), ("last_error", Column::OptionalText), ("dead", Column::Boolean),
+    ]),
+];
+
+fn read_column(row: &rusqlite::Row, index: usize, column: &Column) -> rusqlite::Result<Box<dyn ToSql + Sync>> {
+    return Ok(match column {
+        Column::Text => Box::new(row.get::<_, String>(index)?),
+        Column::OptionalText => Box::new(row.get::<_, Option<String>>(index)?),
+        Column::Integer => Box::new(row.get::<_, i64>(index)?),
+        Column::OptionalInteger => Box::new(row.get::<_, Option<i64>>(index)?),
+        Column::Serial => Box::new(row.get::<_, i64>(index)?),
+        Column::Boolean => Box::new(row.get::<_, bool>(index)?),
+    });
+}
+
+/// Copies everything in a sqlite database into an empty postgres one, ids included, in a single transaction.
+/// A copy of the sqlite database is migrated to the latest version first, so both have the same columns, the
+/// database itself is only read. Returns how many rows were copied from each table
+pub async fn import_sqlite(path: &str, url: &str) -> RelayResult<Vec<(&'static str, usize)>>
+{
+    // Opening a path that isn't there would make an empty database, and import nothing
+    if !Path::new(path).exists() {
+        return Err(RelayError::NotFound(format!("sqlite database {}", path)));
+    }
+    let copy = std::env::temp_dir().join(format!("relay_import_copy_{}.db", std::process::id())).to_string_lossy().into_owned();
+    std::fs::remove_file(&copy).ok();
+
+    let res = copy_sqlite(path, &copy, url).await;
+    std::fs::remove_file(&copy).ok();
+    return res;
+}
+
+async fn copy_sqlite(path: &str, copy: &str, url: &str) -> RelayResult<Vec<(&'static str, usize)>>
+{
+    let (path, copy) = (path.to_owned(), copy.to_owned());
+    let migrated = copy.clone();
+    blocking(move || {
+        // VACUUM INTO also takes what is still in the write ahead log, unlike copying the file
+        let source = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        source.execute("VACUUM INTO ?1", [&migrated])?;
+        migrations::migrate(&Connection::open(&migrated)?, false)?;
+        return Ok(());
+    }).await?;
+    let store = PostgresStore::open(url).await?;
+
+    return store.run(move |database| {
+        let sqlite = Connection::open_with_flags(copy, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let mut transaction = database.transaction()?;
+
+        // Copying on top of existing rows would clash on ids, or worse, mix two bridges' messages
+        for (table, _) in TABLES {
+            let count: i64 = transaction.query_one(&format!("SELECT COUNT(*) FROM {}", table), &[])?.get(0);
+            if count > 0 {
+                return Err(RelayError::Storage(format!("{} already has {} rows, only an empty database can be imported into", table, count)));
+            }
+        }
+
+        let mut copied = Vec::new();
+        for (table, columns) in TABLES {
+            let names = columns.iter().map(|(name, _)| *name).collect::<Vec<&str>>().join(", ");
+            let placeholders = (1..=columns.len()).map(|i| format!("${}", i)).collect::<Vec<String>>().join(", ");
+            let insert = transaction.prepare(&format!("INSERT INTO {} ({}) VALUES ({})", table, names, placeholders))?;
+
+            let mut select = sqlite.prepare(&format!("SELECT {} FROM {}", names, table))?;
+            let mut rows = select.query(())?;
+            let mut count = 0;
+            while let Some(row) = rows.next()? {
+                let mut values: Vec<Box<dyn ToSql + Sync>> = Vec::new();
+                for (i, (_, column)) in columns.iter().enumerate() {
+                    values.push(read_column(row, i, column)?);
+                }
+                let params = values.iter().map(|value| value.as_ref()).collect::<Vec<&(dyn ToSql + Sync)>>();
+                transaction.execute(&insert, &params)?;
+                count += 1;
+            }
+
+            // The ids were copied as they were, so the sequences have to continue after them
+            for (name, _) in columns.iter().filter(|(_, column)| matches!(column, Column::Serial)) {
+                transaction.execute(
+                    &format!("SELECT setval(pg_get_serial_sequence('{0}', '{1}'), COALESCE((SELECT MAX({1}) FROM {0}), 0) + 1, false)", table, name),
+                    &[],
+                )?;
+            }
+            copied.push((*table, count));
+        }
+
+        transaction.commit()?;
+        return Ok(copied);
+    }).await;
+}
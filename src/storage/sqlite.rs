@@ -0,0 +1,438 @@
+// Storage in a sqlite file next to the relay, the default
+
+use std::path::Path;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, OpenFlags};
+
+use crate::chat_service::{Message, OutboxItem, Storage};
+use crate::error::RelayResult;
+use crate::Entry;
+use crate::migrations::{self, Migration};
+
+use super::blocking;
+
+// Connections kept open at once. SQLite only has one writer, the rest are for reads which WAL lets run alongside it
+const POOL_SIZE: u32 = 8;
+// How long a connection waits for another one's write to finish before giving up
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+// Prepared statements kept per connection, there are about as many as there are queries below
+const STATEMENT_CACHE: usize = 64;
+
+#[derive(Clone)]
+pub struct SqliteStore {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+impl SqliteStore {
+    /// Opens (or creates) the database at path and brings its schema up to date
+    pub async fn open(path: &str) -> RelayResult<SqliteStore>
+    {
+        let manager = SqliteConnectionManager::file(path).with_init(|database| {
+            // WAL lets reads carry on while something is being written, instead of failing with SQLITE_BUSY
+            database.execute_batch("PRAGMA journal_mode=WAL; PRAGMA synchronous=NORMAL;")?;
+            database.busy_timeout(BUSY_TIMEOUT)?;
+            database.set_prepared_statement_cache_capacity(STATEMENT_CACHE);
+            return Ok(());
+        });
+        let pool = Pool::builder().max_size(POOL_SIZE).build(manager)?;
+
+        let store = SqliteStore { pool: pool };
+        store.run(|database| migrations::migrate(database, false)).await?;
+        return Ok(store);
+    }
+
+    /// Opens the database without changing it, and returns the migrations opening it normally would apply
+    pub async fn pending_migrations(path: &str) -> RelayResult<Vec<&'static Migration>>
+    {
+        // Opening would create the file, a database that isn't there yet gets every migration
+        if !Path::new(path).exists() {
+            return migrations::pending_after(0);
+        }
+        let path = path.to_owned();
+        return blocking(move || {
+            let database = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+            return migrations::migrate(&database, true);
+        }).await;
+    }
+
+    async fn run<T, F>(&self, work: F) -> RelayResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> RelayResult<T> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        return blocking(move || {
+            let database = pool.get()?;
+            return work(&database);
+        }).await;
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStore {
+    async fn create_message(&self, source: Message, relayed: Message) -> RelayResult<()>
+    {
+        return self.run(move |database| {
+            database.prepare_cached("
+            INSERT OR IGNORE INTO messages (service_org, server_id_org, room_id_org, id_org, service_out, server_id_out, room_id_out, id_out, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, CAST(strftime('%s', 'now') AS INTEGER));")?
+            .execute((source.service, source.server_id, source.room_id, source.id, relayed.service, relayed.server_id, relayed.room_id, relayed.id))?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn message_origin(&self, relayed: Message) -> RelayResult<Option<Message>>
+    {
+        return self.run(move |database| message_origin(database, &relayed)).await;
+    }
+
+    async fn message_relays(&self, source: Message) -> RelayResult<Vec<Message>>
+    {
+        return self.run(move |database| message_relays(database, &source)).await;
+    }
+
+    async fn delete_message(&self, msg: Message) -> RelayResult<()>
+    {
+        return self.run(move |database| {
+            let origin = message_origin(database, &msg)?.unwrap_or(msg);
+            database.prepare_cached("DELETE FROM messages WHERE service_org=? AND room_id_org=? AND id_org=?")?
+            .execute((origin.service, origin.room_id, origin.id))?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn delete_relayed_message(&self, relayed: Message) -> RelayResult<()>
+    {
+        return self.run(move |database| {
+            database.prepare_cached("DELETE FROM messages WHERE service_out=? AND room_id_out=? AND id_out=?")?
+            .execute((relayed.service, relayed.room_id, relayed.id))?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn create_reaction(&self, source: Message, relayed: Message) -> RelayResult<()>
+    {
+        return self.run(move |database| {
+            database.prepare_cached("
+            INSERT OR IGNORE INTO reactions (service_org, server_id_org, room_id_org, id_org, service_out, server_id_out, room_id_out, id_out)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?);")?
+            .execute((source.service, source.server_id, source.room_id, source.id, relayed.service, relayed.server_id, relayed.room_id, relayed.id))?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn reaction_relays(&self, source: Message) -> RelayResult<Vec<Message>>
+    {
+        return self.run(move |database| {
+            return query_messages(database, "SELECT service_out, server_id_out, room_id_out, id_out FROM reactions WHERE service_org=:s AND server_id_org=:sid AND room_id_org=:rid AND id_org=:id", &source);
+        }).await;
+    }
+
+    async fn reaction_origins(&self, relayed: Message) -> RelayResult<Vec<Message>>
+    {
+        return self.run(move |database| {
+            return query_messages(database, "SELECT service_org, server_id_org, room_id_org, id_org FROM reactions WHERE service_out=:s AND server_id_out=:sid AND room_id_out=:rid AND id_out=:id", &relayed);
+        }).await;
+    }
+
+    async fn delete_reaction(&self, reaction: Message) -> RelayResult<()>
+    {
+        return self.run(move |database| {
+            database.prepare_cached("DELETE FROM reactions WHERE (service_org=?1 AND room_id_org=?2 AND id_org=?3) OR (service_out=?1 AND room_id_out=?2 AND id_out=?3)")?
+            .execute((reaction.service, reaction.room_id, reaction.id))?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn create_thread(&self, thread: Message, root: Message) -> RelayResult<()>
+    {
+        return self.run(move |database| {
+            database.prepare_cached("
+            INSERT OR IGNORE INTO threads (service_org, server_id_org, room_id_org, id_org, service_out, server_id_out, room_id_out, id_out)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?);")?
+            .execute((thread.service, thread.server_id, thread.room_id, thread.id, root.service, root.server_id, root.room_id, root.id))?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn thread_root(&self, thread: Message) -> RelayResult<Option<Message>>
+    {
+        return self.run(move |database| {
+            return Ok(query_messages(database, "SELECT service_out, server_id_out, room_id_out, id_out FROM threads WHERE service_org=:s AND server_id_org=:sid AND room_id_org=:rid AND id_org=:id", &thread)?.pop());
+        }).await;
+    }
+
+    async fn root_thread(&self, root: Message) -> RelayResult<Option<Message>>
+    {
+        return self.run(move |database| {
+            return Ok(query_messages(database, "SELECT service_org, server_id_org, room_id_org, id_org FROM threads WHERE service_out=:s AND server_id_out=:sid AND room_id_out=:rid AND id_out=:id", &root)?.pop());
+        }).await;
+    }
+
+    async fn avatar_mxc(&self, hash: &str) -> RelayResult<Option<String>>
+    {
+        let hash = hash.to_owned();
+        return self.run(move |database| query_optional(database, "SELECT mxc FROM avatars WHERE hash=?", &[hash.as_str()])).await;
+    }
+
+    async fn create_avatar(&self, hash: &str, mxc: &str) -> RelayResult<()>
+    {
+        let (hash, mxc) = (hash.to_owned(), mxc.to_owned());
+        return self.run(move |database| {
+            database.prepare_cached("INSERT OR REPLACE INTO avatars (hash, mxc) VALUES (?, ?)")?.execute((hash, mxc))?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn puppet_avatar(&self, user_id: &str) -> RelayResult<Option<String>>
+    {
+        let user_id = user_id.to_owned();
+        return self.run(move |database| query_optional(database, "SELECT avatar_hash FROM puppets WHERE user_id=?", &[user_id.as_str()])).await;
+    }
+
+    async fn set_puppet_avatar(&self, user_id: &str, hash: &str) -> RelayResult<()>
+    {
+        let (user_id, hash) = (user_id.to_owned(), hash.to_owned());
+        return self.run(move |database| {
+            database.prepare_cached("
+            INSERT INTO puppets (user_id, avatar_hash) VALUES (?1, ?2)
+            ON CONFLICT(user_id) DO UPDATE SET avatar_hash=?2")?
+            .execute((user_id, hash))?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn puppet_name(&self, user_id: &str, room_id: &str) -> RelayResult<Option<String>>
+    {
+        let (user_id, room_id) = (user_id.to_owned(), room_id.to_owned());
+        return self.run(move |database| {
+            return query_optional(database, "SELECT displayname FROM puppet_names WHERE user_id=? AND room_id=?", &[user_id.as_str(), room_id.as_str()]);
+        }).await;
+    }
+
+    async fn set_puppet_name(&self, user_id: &str, room_id: &str, displayname: &str) -> RelayResult<()>
+    {
+        let (user_id, room_id, displayname) = (user_id.to_owned(), room_id.to_owned(), displayname.to_owned());
+        return self.run(move |database| {
+            database.prepare_cached("
+            INSERT INTO puppet_names (user_id, room_id, displayname) VALUES (?1, ?2, ?3)
+            ON CONFLICT(user_id, room_id) DO UPDATE SET displayname=?3")?
+            .execute((user_id, room_id, displayname))?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn clear_puppet_room_names(&self, user_id: &str) -> RelayResult<()>
+    {
+        let user_id = user_id.to_owned();
+        return self.run(move |database| {
+            database.prepare_cached("DELETE FROM puppet_names WHERE user_id=? AND room_id!=''")?.execute([user_id])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn rooms(&self) -> RelayResult<Vec<Entry>>
+    {
+        return self.run(move |database| {
+            let mut stmt = database.prepare_cached("SELECT discord, discord_guild, matrix, webhook, max_upload_size, configured FROM rooms ORDER BY discord")?;
+            let iter = stmt.query_map((), |row| {
+                Ok(Entry {
+                    discord: row.get(0)?,
+                    discord_guild: row.get(1)?,
+                    matrix: row.get(2)?,
+                    webhook: row.get(3)?,
+                    max_upload_size: row.get::<_, Option<i64>>(4)?.map(|size| size as u64),
+                    configured: row.get(5)?,
+                })
+            })?;
+
+            let mut out: Vec<Entry> = Vec::new();
+            for room in iter {
+                out.push(room?);
+            }
+            return Ok(out);
+        }).await;
+    }
+
+    async fn create_room(&self, room: &Entry) -> RelayResult<()>
+    {
+        let room = room.clone();
+        return self.run(move |database| {
+            // REPLACE drops the rows either unique column conflicts with
+            database.prepare_cached("INSERT OR REPLACE INTO rooms (discord, discord_guild, matrix, webhook, max_upload_size, configured) VALUES (?, ?, ?, ?, ?, ?)")?
+            .execute((room.discord, room.discord_guild, room.matrix, room.webhook, room.max_upload_size.map(|size| size as i64), room.configured))?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn delete_room(&self, matrix: &str) -> RelayResult<()>
+    {
+        let matrix = matrix.to_owned();
+        return self.run(move |database| {
+            database.prepare_cached("DELETE FROM rooms WHERE matrix=?")?.execute([matrix])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn channel_webhook(&self, channel: &str) -> RelayResult<Option<(String, String)>>
+    {
+        let channel = channel.to_owned();
+        return self.run(move |database| {
+            let res = database.prepare_cached("SELECT id, token FROM webhooks WHERE channel=?")?
+            .query_row([channel], |row| Ok((row.get(0)?, row.get(1)?)));
+            match res {
+                Ok(webhook) => return Ok(Some(webhook)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+                Err(err) => return Err(err.into()),
+            }
+        }).await;
+    }
+
+    async fn set_channel_webhook(&self, channel: &str, id: &str, token: &str) -> RelayResult<()>
+    {
+        let (channel, id, token) = (channel.to_owned(), id.to_owned(), token.to_owned());
+        return self.run(move |database| {
+            database.prepare_cached("INSERT OR REPLACE INTO webhooks (channel, id, token) VALUES (?, ?, ?)")?.execute((channel, id, token))?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn enqueue_outbox(&self, destination: &str, payload: &str, now: i64) -> RelayResult<i64>
+    {
+        let (destination, payload) = (destination.to_owned(), payload.to_owned());
+        return self.run(move |database| {
+            database.prepare_cached("
+            INSERT INTO outbox (destination, payload, attempts, next_attempt, dead)
+            VALUES (?, ?, 0, ?, 0);")?
+            .execute((destination, payload, now))?;
+            return Ok(database.last_insert_rowid());
+        }).await;
+    }
+
+    async fn outbox_heads(&self) -> RelayResult<Vec<OutboxItem>>
+    {
+        return self.run(|database| {
+            return query_outbox(database, "
+            SELECT id, destination, payload, attempts, next_attempt, last_error, dead FROM outbox
+            WHERE id IN (SELECT MIN(id) FROM outbox WHERE dead=0 GROUP BY destination)
+            ORDER BY id");
+        }).await;
+    }
+
+    async fn outbox_done(&self, id: i64) -> RelayResult<()>
+    {
+        return self.run(move |database| {
+            database.prepare_cached("DELETE FROM outbox WHERE id=?")?.execute([id])?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn outbox_retry(&self, id: i64, attempts: u32, next_attempt: i64, error: &str) -> RelayResult<()>
+    {
+        let error = error.to_owned();
+        return self.run(move |database| {
+            database.prepare_cached("UPDATE outbox SET attempts=?, next_attempt=?, last_error=? WHERE id=?")?
+            .execute((attempts, next_attempt, error, id))?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn outbox_dead(&self, id: i64, attempts: u32, error: &str) -> RelayResult<()>
+    {
+        let error = error.to_owned();
+        return self.run(move |database| {
+            database.prepare_cached("UPDATE outbox SET attempts=?, last_error=?, dead=1 WHERE id=?")?.execute((attempts, error, id))?;
+            return Ok(());
+        }).await;
+    }
+
+    async fn dead_letters(&self) -> RelayResult<Vec<OutboxItem>>
+    {
+        return self.run(|database| {
+            return query_outbox(database, "SELECT id, destination, payload, attempts, next_attempt, last_error, dead FROM outbox WHERE dead=1 ORDER BY id");
+        }).await;
+    }
+
+    async fn requeue_dead_letter(&self, id: i64, now: i64) -> RelayResult<()>
+    {
+        return self.run(move |database| {
+            database.prepare_cached("UPDATE outbox SET dead=0, attempts=0, next_attempt=? WHERE id=? AND dead=1")?.execute((now, id))?;
+            return Ok(());
+        }).await;
+    }
+}
+
+// Runs a query which selects (service, server_id, room_id, id) columns, with the message bound to :s, :sid, :rid and :id
+fn query_messages(database: &Connection, sql: &str, msg: &Message) -> RelayResult<Vec<Message>>
+{
+    let mut stmt = database.prepare_cached(sql)?;
+    let iter = stmt.query_map(&[
+        (":s", msg.service.as_str()),
+        (":sid", msg.server_id.as_str()),
+        (":rid", msg.room_id.as_str()),
+        (":id", msg.id.as_str()),
+    ], |row| {
+        Ok(Message {
+            service: row.get(0)?,
+            server_id: row.get(1)?,
+            room_id: row.get(2)?,
+            id: row.get(3)?,
+        })
+    })?;
+
+    let mut out: Vec<Message> = Vec::new();
+    for msg in iter {
+        out.push(msg?);
+    }
+
+    return Ok(out);
+}
+
+// Runs a query which selects a single optional value
+fn query_optional(database: &Connection, sql: &str, params: &[&str]) -> RelayResult<Option<String>>
+{
+    let mut stmt = database.prepare_cached(sql)?;
+    let res = stmt.query_row(rusqlite::params_from_iter(params.iter()), |row| row.get(0));
+    match res {
+        Ok(value) => return Ok(Some(value)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+}
+
+fn query_outbox(database: &Connection, sql: &str) -> RelayResult<Vec<OutboxItem>>
+{
+    let mut stmt = database.prepare_cached(sql)?;
+    let iter = stmt.query_map((), |row| {
+        Ok(OutboxItem {
+            id: row.get(0)?,
+            destination: row.get(1)?,
+            payload: row.get(2)?,
+            attempts: row.get(3)?,
+            next_attempt: row.get(4)?,
+            last_error: row.get(5)?,
+            dead: row.get(6)?,
+        })
+    })?;
+
+    let mut out: Vec<OutboxItem> = Vec::new();
+    for item in iter {
+        out.push(item?);
+    }
+    return Ok(out);
+}
+
+fn message_origin(database: &Connection, relayed: &Message) -> RelayResult<Option<Message>>
+{
+    let out = query_messages(database, "SELECT service_org, server_id_org, room_id_org, id_org FROM messages WHERE service_out=:s AND server_id_out=:sid AND room_id_out=:rid AND id_out=:id", relayed)?;
+    return Ok(out.into_iter().next());
+}
+
+fn message_relays(database: &Connection, source: &Message) -> RelayResult<Vec<Message>>
+{
+    return query_messages(database, "SELECT service_out, server_id_out, room_id_out, id_out FROM messages WHERE service_org=:s AND server_id_org=:sid AND room_id_org=:rid AND id_org=:id ORDER BY id", source);
+}